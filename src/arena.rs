@@ -0,0 +1,202 @@
+//
+// arena.rs
+// Hash-consed specs stored in an arena
+//
+
+use std::collections::HashMap;
+
+use nfa::Label;
+use nfa::Spec;
+use rules;
+
+/// Identifier of a node in a `SpecArena`.
+pub type SpecID = usize;
+
+/// A node of a spec, whose operands are other nodes of the same arena.
+#[derive(PartialEq, Eq, Debug, Hash, Clone)]
+pub enum SpecNode {
+    Single(Label),
+    Union(SpecID, SpecID),
+    Concat(SpecID, SpecID),
+    Star(SpecID),
+    /// Counted repetition `s{min,max}`, with no upper bound if `max` is
+    /// `None`; it is only expanded when building an automaton.
+    Repeat(SpecID, u32, Option<u32>),
+    /// Capturing group `i`, like `Spec::Group`.
+    Group(usize, SpecID),
+    Intersection(SpecID, SpecID),
+    Complement(SpecID)
+}
+
+/// An arena of spec nodes, shared as a DAG.
+///
+/// Nodes are interned: building a node equal to one already in the
+/// arena returns the existing id, so a subexpression used several times
+/// is stored once, and two specs of the same arena are equal exactly
+/// when their ids are.
+pub struct SpecArena {
+    nodes: Vec<SpecNode>,
+    ids: HashMap<SpecNode, SpecID>
+}
+
+impl Default for SpecArena {
+    fn default() -> SpecArena {
+        SpecArena::new()
+    }
+}
+
+impl SpecArena {
+    pub fn new() -> SpecArena {
+        SpecArena { nodes: Vec::new(), ids: HashMap::new() }
+    }
+
+    /// Returns the id of `node`, adding it to the arena if it is new.
+    pub fn intern(&mut self, node: SpecNode) -> SpecID {
+        if let Some(&id) = self.ids.get(&node) {
+            return id;
+        }
+        let id = self.nodes.len();
+        self.nodes.push(node.clone());
+        self.ids.insert(node, id);
+        id
+    }
+
+    // helper methods for building nodes, like the ones of Spec
+    pub fn single(&mut self, l: Label) -> SpecID {
+        self.intern(SpecNode::Single(l))
+    }
+
+    pub fn union(&mut self, s1: SpecID, s2: SpecID) -> SpecID {
+        self.intern(SpecNode::Union(s1, s2))
+    }
+
+    pub fn concat(&mut self, s1: SpecID, s2: SpecID) -> SpecID {
+        self.intern(SpecNode::Concat(s1, s2))
+    }
+
+    pub fn star(&mut self, s: SpecID) -> SpecID {
+        self.intern(SpecNode::Star(s))
+    }
+
+    pub fn intersection(&mut self, s1: SpecID, s2: SpecID) -> SpecID {
+        self.intern(SpecNode::Intersection(s1, s2))
+    }
+
+    pub fn complement(&mut self, s: SpecID) -> SpecID {
+        self.intern(SpecNode::Complement(s))
+    }
+
+    pub fn repeat(&mut self, s: SpecID, min: u32, max: Option<u32>) -> SpecID {
+        self.intern(SpecNode::Repeat(s, min, max))
+    }
+
+    pub fn node(&self, id: SpecID) -> &SpecNode {
+        &self.nodes[id]
+    }
+
+    /// Returns the number of distinct nodes in the arena.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns whether the arena has no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns whether the node `id` has an intersection or a complement,
+    /// like `Spec::has_boolean_ops`.
+    pub fn has_boolean_ops(&self, id: SpecID) -> bool {
+        let mut seen = vec![false; self.nodes.len()];
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if seen[id] {
+                continue;
+            }
+            seen[id] = true;
+            match self.nodes[id] {
+                SpecNode::Single(_) => (),
+                SpecNode::Intersection(_, _) | SpecNode::Complement(_) => return true,
+                SpecNode::Union(s1, s2) | SpecNode::Concat(s1, s2) => stack.extend_from_slice(&[s1, s2]),
+                SpecNode::Star(s) | SpecNode::Repeat(s, _, _) | SpecNode::Group(_, s) => stack.push(s)
+            }
+        }
+        false
+    }
+
+    /// Adds the nodes of the tree `s` to the arena, sharing its equal
+    /// subtrees.
+    pub fn from_spec(&mut self, s: &Spec) -> SpecID {
+        let node = match *s {
+            Spec::Single(ref l) => SpecNode::Single(l.clone()),
+            Spec::Union(ref s1, ref s2) => SpecNode::Union(self.from_spec(s1), self.from_spec(s2)),
+            Spec::Concat(ref s1, ref s2) => SpecNode::Concat(self.from_spec(s1), self.from_spec(s2)),
+            Spec::Star(ref s) => SpecNode::Star(self.from_spec(s)),
+            Spec::Group(i, ref s) => SpecNode::Group(i, self.from_spec(s)),
+            Spec::Intersection(ref s1, ref s2) => SpecNode::Intersection(self.from_spec(s1), self.from_spec(s2)),
+            Spec::Complement(ref s) => SpecNode::Complement(self.from_spec(s))
+        };
+        self.intern(node)
+    }
+
+    /// Expands the node `id` into a tree, copying its shared subtrees and
+    /// unrolling its counted repetitions, for the functions that take a
+    /// `Spec`; the automata of rules are built from the arena instead.
+    pub fn to_spec(&self, id: SpecID) -> Spec {
+        match self.nodes[id] {
+            SpecNode::Single(ref l) => Spec::Single(l.clone()),
+            SpecNode::Union(s1, s2) => Spec::Union(Box::new(self.to_spec(s1)), Box::new(self.to_spec(s2))),
+            SpecNode::Concat(s1, s2) => Spec::Concat(Box::new(self.to_spec(s1)), Box::new(self.to_spec(s2))),
+            SpecNode::Star(s) => Spec::Star(Box::new(self.to_spec(s))),
+            SpecNode::Repeat(s, min, max) => rules::repeater_range(Box::new(self.to_spec(s)), min, max),
+            SpecNode::Group(i, s) => Spec::Group(i, Box::new(self.to_spec(s))),
+            SpecNode::Intersection(s1, s2) => Spec::Intersection(Box::new(self.to_spec(s1)), Box::new(self.to_spec(s2))),
+            SpecNode::Complement(s) => Spec::Complement(Box::new(self.to_spec(s)))
+        }
+    }
+}
+
+#[test]
+fn test_interning() {
+    let mut arena = SpecArena::new();
+    let a = arena.single(Label::Symbol('a'));
+    let b = arena.single(Label::Symbol('b'));
+    let ab1 = arena.concat(a, b);
+    let ab2 = arena.concat(a, b);
+    let ba = arena.concat(b, a);
+
+    assert_eq!(ab1, ab2);
+    assert!(ab1 != ba);
+    assert_eq!(arena.single(Label::Symbol('a')), a);
+    assert_eq!(arena.len(), 4);
+
+    let spec = *Spec::union(Spec::concat(Spec::single(Label::Symbol('a')), Spec::single(Label::Symbol('b'))),
+                            Spec::star(Spec::concat(Spec::single(Label::Symbol('a')), Spec::single(Label::Symbol('b')))));
+    let id = arena.from_spec(&spec);
+    let star = arena.star(ab1);
+    assert_eq!(arena.node(id), &SpecNode::Union(ab1, star));
+    assert_eq!(arena.len(), 6);
+    assert_eq!(arena.to_spec(id), spec);
+
+    let r = arena.repeat(ab1, 2, Some(3));
+    assert_eq!(arena.to_spec(r), rules::repeater_range(Box::new(arena.to_spec(ab1)), 2, Some(3)));
+}
+
+#[test]
+fn test_arena_nfa() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+
+    let mut arena = SpecArena::new();
+    let patterns = [r"if", r"[a-z][a-z0-9]{0,63}", r"\d{1,100}", r"(ab|c)+d?"];
+    let ids: Vec<SpecID> = patterns.iter().map(|p| rules::regex_to_spec_id(&mut arena, &Expr::parse(p).unwrap())).collect();
+    let nfa = NFABuilder::build_from_arena(&arena, &ids);
+    let mut specs = patterns.iter().map(|p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap())).collect();
+    let tree_nfa = NFABuilder::build_from_specs(&mut specs);
+
+    // the counts of repetitions do not change the size of the arena
+    assert!(arena.len() < 25, "{} nodes", arena.len());
+    for w in ["if", "ifx", "a0", "x99", "123", "abcd", "ababc", "d", ""].iter() {
+        assert_eq!(nfa.longest_match(w), tree_nfa.longest_match(w), "{}", w);
+    }
+}
@@ -0,0 +1,187 @@
+//
+// classes.rs
+// Input equivalence classes
+//
+
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::char;
+
+use nfa::Label;
+
+const MAX_CODE_POINT: u32 = 0x10FFFF;
+
+/// A partition of the characters into classes that a set of labels
+/// cannot tell apart: two characters are in the same class if every
+/// label matches both or neither of them.
+///
+/// Class 0 holds the characters not matched by any label (the ones
+/// only an `Any` transition can take), and may be empty.
+#[derive(Clone)]
+pub struct CharClasses {
+    // disjoint intervals of code points covering all of them, in order,
+    // with the class of each
+    intervals: Vec<(u32, u32, usize)>,
+    count: usize
+}
+
+impl CharClasses {
+    /// Computes the classes for the labels in `labels`.
+    pub fn new<'a, I: Iterator<Item=&'a Label>>(labels: I) -> CharClasses {
+        let labels: Vec<&Label> = labels.filter(|l| matches!(**l, Label::Symbol(_) | Label::Class(_)))
+            .collect::<BTreeSet<&Label>>().into_iter().collect();
+
+        // the labels can only tell characters apart at the bounds of their ranges
+        let mut bounds = BTreeSet::new();
+        bounds.insert(0);
+        bounds.insert(MAX_CODE_POINT + 1);
+        for l in labels.iter() {
+            for (lo, hi) in l.ranges() {
+                bounds.insert(lo as u32);
+                bounds.insert(hi as u32 + 1);
+            }
+        }
+
+        let mut signatures = HashMap::new();
+        signatures.insert(vec![false; labels.len()], 0);
+        let bounds: Vec<u32> = bounds.into_iter().collect();
+        let mut intervals = Vec::with_capacity(bounds.len());
+        for w in bounds.windows(2) {
+            let (lo, hi) = (w[0], w[1] - 1);
+            let class = match first_char(lo, hi) {
+                Some(c) => {
+                    let sig: Vec<bool> = labels.iter().map(|l| l.matches(c)).collect();
+                    let next = signatures.len();
+                    *signatures.entry(sig).or_insert(next)
+                },
+                None => 0   // only surrogates, which are not characters
+            };
+            intervals.push((lo, hi, class));
+        }
+
+        CharClasses { intervals, count: signatures.len() }
+    }
+
+    /// Returns the number of classes, including class 0.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn class_of(&self, c: char) -> usize {
+        let c = c as u32;
+        let i = match self.intervals.binary_search_by(|&(lo, _, _)| lo.cmp(&c)) {
+            Ok(i) => i,
+            Err(i) => i - 1   // safe because the first interval starts at 0
+        };
+        self.intervals[i].2
+    }
+
+    /// Returns the ranges of characters in class `k`, in order.
+    pub fn ranges(&self, k: usize) -> Vec<(char, char)> {
+        let mut res: Vec<(char, char)> = Vec::new();
+        for &(lo, hi, class) in self.intervals.iter() {
+            if class != k {
+                continue;
+            }
+            for (lo, hi) in valid_ranges(lo, hi) {
+                match res.last_mut() {
+                    Some(last) if last.1 as u32 + 1 == lo as u32 => {
+                        last.1 = hi;
+                        continue;
+                    },
+                    _ => ()
+                }
+                res.push((lo, hi));
+            }
+        }
+        res
+    }
+
+    /// Returns a character of class `k`, preferably a letter or a digit,
+    /// or `None` if the class is empty.
+    pub fn representative(&self, k: usize) -> Option<char> {
+        let ranges = self.ranges(k);
+        for &(plo, phi) in [('a', 'z'), ('A', 'Z'), ('0', '9'), (' ', '~')].iter() {
+            for &(lo, hi) in ranges.iter() {
+                if lo <= phi && hi >= plo {
+                    return Some(if lo > plo { lo } else { plo });
+                }
+            }
+        }
+        ranges.first().map(|&(lo, _)| lo)
+    }
+
+    /// Returns a label matching exactly the characters in class `k`;
+    /// class 0 is represented by `Any`.
+    pub fn label(&self, k: usize) -> Label {
+        if k == 0 {
+            return Label::Any;
+        }
+        let ranges = self.ranges(k);
+        if ranges.len() == 1 && ranges[0].0 == ranges[0].1 {
+            Label::Symbol(ranges[0].0)
+        } else {
+            Label::Class(ranges)
+        }
+    }
+}
+
+fn first_char(lo: u32, hi: u32) -> Option<char> {
+    valid_ranges(lo, hi).first().map(|&(c, _)| c)
+}
+
+// the ranges of characters in the code points lo..hi, which can include
+// surrogates
+fn valid_ranges(lo: u32, hi: u32) -> Vec<(char, char)> {
+    let mut res = Vec::with_capacity(2);
+    let parts = [(lo, if hi < 0xD800 { hi } else { 0xD7FF }),
+                 (if lo > 0xDFFF { lo } else { 0xE000 }, hi)];
+    for &(l, h) in parts.iter() {
+        if l <= h {
+            if let (Some(l), Some(h)) = (char::from_u32(l), char::from_u32(h)) {
+                res.push((l, h));
+            }
+        }
+    }
+    res
+}
+
+#[test]
+fn test_classes() {
+    let labels = [Label::Symbol('i'), Label::Class(vec![('a', 'z')]),
+                      Label::Class(vec![('0', '9'), ('a', 'f')]), Label::Any, Label::Epsilon,
+                      Label::Symbol('i')];
+    let cls = CharClasses::new(labels.iter());
+
+    // others, [0-9], [a-f], [g-h] and [j-z], i
+    assert_eq!(cls.len(), 5);
+    assert_eq!(cls.class_of('!'), 0);
+    assert_eq!(cls.class_of('\u{10FFFF}'), 0);
+    assert_eq!(cls.class_of('0'), cls.class_of('9'));
+    assert_eq!(cls.class_of('a'), cls.class_of('f'));
+    assert_eq!(cls.class_of('g'), cls.class_of('z'));
+    assert!(cls.class_of('g') != cls.class_of('i'));
+    assert!(cls.class_of('a') != cls.class_of('0'));
+
+    let gz = cls.class_of('g');
+    assert_eq!(cls.ranges(gz), vec![('g', 'h'), ('j', 'z')]);
+    assert_eq!(cls.label(gz), Label::Class(vec![('g', 'h'), ('j', 'z')]));
+    assert_eq!(cls.label(cls.class_of('i')), Label::Symbol('i'));
+    assert_eq!(cls.label(0), Label::Any);
+    assert_eq!(cls.representative(0), Some('A'));
+    assert_eq!(cls.ranges(0)[0], ('\0', '/'));
+    assert_eq!(cls.ranges(0)[2], ('{', '\u{D7FF}'));
+    assert_eq!(cls.ranges(0).last(), Some(&('\u{E000}', '\u{10FFFF}')));
+}
+
+#[test]
+fn test_classes_surrogates() {
+    let labels = [Label::Class(vec![('\u{D000}', '\u{E100}')])];
+    let cls = CharClasses::new(labels.iter());
+
+    assert_eq!(cls.len(), 2);
+    assert_eq!(cls.ranges(1), vec![('\u{D000}', '\u{D7FF}'), ('\u{E000}', '\u{E100}')]);
+    assert_eq!(cls.class_of('\u{E000}'), 1);
+    assert_eq!(cls.class_of('\u{E101}'), 0);
+}
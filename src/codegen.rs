@@ -0,0 +1,727 @@
+//
+// codegen.rs
+// Generation of Rust source code for lexers
+//
+
+use classes::CharClasses;
+use dfa;
+use dfa::DFA;
+use diagnostics;
+use diagnostics::Diagnostic;
+use nfa::Construction;
+use nfa::DfaLimit;
+use nfa::NFA;
+use nfa::RuleID;
+use parser::LexSpec;
+use tdfa::RegOp;
+use tdfa::TaggedDFA;
+
+// DFA for the rules active in a start condition, along with the
+// global ids of those rules (indexed by the rule tags in the DFA)
+struct ConditionDFA {
+    dfa: NFA,
+    rules: Vec<RuleID>
+}
+
+fn build_condition_dfa(spec: &LexSpec, cond: usize, options: &Options) -> Result<ConditionDFA, Diagnostic> {
+    let dfa = spec.condition_nfa_limited(cond, options.utf8_bytes, options.construction, &options.limit)
+        .and_then(|nfa| nfa.to_dfa_limited(&options.limit));
+    match dfa {
+        Ok(dfa) => Ok(ConditionDFA { dfa: dfa.minimize(), rules: spec.active_rules(cond) }),
+        Err(e) => Err(diagnostics::state_explosion(spec, cond, &e, &options.limit))
+    }
+}
+
+// tagged DFA for the rules active in a start condition, like ConditionDFA
+struct ConditionTDFA {
+    tdfa: TaggedDFA,
+    rules: Vec<RuleID>
+}
+
+// builds the tagged DFAs of all start conditions, with shared classes,
+// from Thompson NFAs, as the other constructions have no group states
+fn build_condition_tdfas(spec: &LexSpec, options: &Options) -> Result<(Vec<ConditionTDFA>, CharClasses), Diagnostic> {
+    let nfas = (0 .. spec.conditions.len()).map(|c| {
+        spec.condition_nfa_limited(c, options.utf8_bytes, Construction::Thompson, &options.limit)
+            .map_err(|e| diagnostics::state_explosion(spec, c, &e, &options.limit))
+    }).collect::<Result<Vec<NFA>, Diagnostic>>()?;
+    let classes = CharClasses::new(nfas.iter().flat_map(|nfa| {
+        (0 .. nfa.num_states()).flat_map(move |s| nfa.get_state(s).unwrap().transitions().iter())
+    }).map(|t| t.label()));
+
+    let mut tdfas = Vec::with_capacity(nfas.len());
+    for (cond, nfa) in nfas.iter().enumerate() {
+        // the plain DFA is built first, as it finds the rule to blame if
+        // it is too large; the tagged one has at least as many states
+        let tdfa = nfa.to_dfa_limited(&options.limit)
+            .and_then(|_| TaggedDFA::build(nfa, classes.clone(), &options.limit))
+            .map_err(|e| diagnostics::state_explosion(spec, cond, &e, &options.limit))?;
+        tdfas.push(ConditionTDFA { tdfa, rules: spec.active_rules(cond) });
+    }
+    Ok((tdfas, classes))
+}
+
+// indents code copied from the spec, keeping the relative indentation
+// of its lines after the first
+fn indent(code: &str, level: usize) -> String {
+    let pad = " ".repeat(level * 4);
+    let common = code.lines().skip(1).filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len()).min().unwrap_or(0);
+
+    let mut res = String::new();
+    for (i, line) in code.lines().enumerate() {
+        res.push_str(&pad);
+        if i == 0 || line.len() < common {
+            res.push_str(line.trim());
+        } else {
+            res.push_str(line[common..].trim_end());
+        }
+        res.push('\n');
+    }
+    res
+}
+
+// smallest unsigned type for values in 0..n
+fn uint_type(n: usize) -> &'static str {
+    match dfa::entry_size(n) {
+        1 => "u8",
+        2 => "u16",
+        _ => "u32"
+    }
+}
+
+fn emit_values(values: &[usize], per_line: usize, out: &mut String) {
+    for line in values.chunks(per_line) {
+        let vs: Vec<String> = line.iter().map(|v| v.to_string()).collect();
+        out.push_str(&format!("    {},\n", vs.join(", ")));
+    }
+}
+
+fn emit_byte_classes(classes: &CharClasses, out: &mut String) {
+    let bytes: Vec<usize> = (0u32..256).map(|b| classes.class_of(b as u8 as char)).collect();
+    out.push_str(&format!("const BYTE_CLASSES: [{}; 256] = [\n", uint_type(classes.len())));
+    emit_values(&bytes, 16, out);
+    out.push_str("];\n\n");
+
+    out.push_str("fn class_of(b: u8) -> usize {
+    BYTE_CLASSES[b as usize] as usize
+}
+
+");
+}
+
+fn emit_char_classes(classes: &CharClasses, out: &mut String) {
+    let nclasses = classes.len();
+
+    let ascii: Vec<usize> = (0u8..128).map(|b| classes.class_of(b as char)).collect();
+    out.push_str(&format!("const ASCII_CLASSES: [{}; 128] = [\n", uint_type(nclasses)));
+    emit_values(&ascii, 16, out);
+    out.push_str("];\n\n");
+
+    // ranges of non-ASCII characters in each class but 0, sorted
+    let mut ranges = Vec::new();
+    for k in 1..nclasses {
+        for (lo, hi) in classes.ranges(k) {
+            if hi as u32 >= 128 {
+                let lo = if (lo as u32) < 128 { '\u{80}' } else { lo };
+                ranges.push((lo, hi, k));
+            }
+        }
+    }
+    ranges.sort();
+    out.push_str(&format!("const CLASS_RANGES: [(char, char, usize); {}] = [\n", ranges.len()));
+    for &(lo, hi, k) in ranges.iter() {
+        out.push_str(&format!("    ({:?}, {:?}, {}),\n", lo, hi, k));
+    }
+    out.push_str("];\n\n");
+
+    out.push_str("fn class_of(c: char) -> usize {
+    if (c as u32) < 128 {
+        return ASCII_CLASSES[c as usize] as usize;
+    }
+    let found = CLASS_RANGES.binary_search_by(|&(lo, hi, _)| {
+        if hi < c {
+            ::std::cmp::Ordering::Less
+        } else if lo > c {
+            ::std::cmp::Ordering::Greater
+        } else {
+            ::std::cmp::Ordering::Equal
+        }
+    });
+    match found {
+        Ok(i) => CLASS_RANGES[i].2,
+        Err(_) => 0
+    }
+}
+
+");
+}
+
+fn emit_classes(classes: &CharClasses, options: &Options, out: &mut String) {
+    out.push_str(&format!("const NUM_CLASSES: usize = {};\n\n", classes.len()));
+    if options.utf8_bytes {
+        emit_byte_classes(classes, out);
+    } else {
+        emit_char_classes(classes, out);
+    }
+}
+
+// emits the automata of all start conditions, with their states numbered
+// in sequence: transitions holds the targets of each state by class, with
+// nstates for no state, and accept the rule recognized in each state plus
+// one, or 0 if it is not accepting
+fn emit_automata(starts: &[usize], transitions: &[usize], accept: &[usize], nclasses: usize, out: &mut String) {
+    let nstates = accept.len();
+    let starts: Vec<String> = starts.iter().map(|s| s.to_string()).collect();
+    out.push_str(&format!("const START_STATES: [usize; {}] = [{}];\n\n", starts.len(), starts.join(", ")));
+
+    let state_type = uint_type(nstates + 1);
+    out.push_str(&format!("const DEAD: {} = {};\n\n", state_type, nstates));
+    out.push_str(&format!("const TRANSITIONS: [{}; {}] = [\n", state_type, transitions.len()));
+    emit_values(transitions, nclasses, out);
+    out.push_str("];\n\n");
+
+    let nrules = accept.iter().cloned().max().unwrap_or(0);
+    out.push_str(&format!("const ACCEPT: [{}; {}] = [\n", uint_type(nrules + 1), nstates));
+    emit_values(accept, 16, out);
+    out.push_str("];\n\n");
+
+    out.push_str("fn transition(state: usize, class: usize) -> Option<usize> {
+    let next = TRANSITIONS[state * NUM_CLASSES + class];
+    if next == DEAD {
+        None
+    } else {
+        Some(next as usize)
+    }
+}
+
+fn accept(state: usize) -> Option<usize> {
+    match ACCEPT[state] {
+        0 => None,
+        r => Some(r as usize - 1)
+    }
+}
+
+");
+}
+
+fn emit_tables(dfas: &Vec<ConditionDFA>, options: &Options, out: &mut String) {
+    let classes = CharClasses::new(dfas.iter().flat_map(|cd| {
+        (0 .. cd.dfa.num_states()).flat_map(move |s| cd.dfa.get_state(s).unwrap().transitions().iter())
+    }).map(|t| t.label()));
+    emit_classes(&classes, options, out);
+
+    let nstates = dfas.iter().map(|cd| cd.dfa.num_states()).sum();
+    let mut starts = Vec::with_capacity(dfas.len());
+    let mut transitions = Vec::with_capacity(nstates * classes.len());
+    let mut accept = Vec::with_capacity(nstates);
+    for cd in dfas {
+        let off = accept.len();
+        starts.push(cd.dfa.start_state() + off);
+        let table = DFA::with_classes(&cd.dfa, classes.clone());
+        for sid in 0 .. table.num_states() {
+            transitions.extend((0 .. classes.len()).map(|k| match table.next_class(sid, k) {
+                dfa::DEAD => nstates,
+                target => target + off
+            }));
+            accept.push(cd.dfa.get_state(sid).unwrap().rule().map(|r| cd.rules[r] + 1).unwrap_or(0));
+        }
+    }
+    emit_automata(&starts, &transitions, &accept, classes.len(), out);
+}
+
+fn emit_tagged_tables(tdfas: &Vec<ConditionTDFA>, classes: &CharClasses, options: &Options, out: &mut String) {
+    emit_classes(classes, options, out);
+
+    let nstates = tdfas.iter().map(|ct| ct.tdfa.num_states()).sum();
+    let mut starts = Vec::with_capacity(tdfas.len());
+    let mut transitions = Vec::with_capacity(nstates * classes.len());
+    let mut accept = Vec::with_capacity(nstates);
+    for ct in tdfas {
+        let off = accept.len();
+        starts.push(off);
+        for sid in 0 .. ct.tdfa.num_states() {
+            transitions.extend((0 .. classes.len()).map(|k| match ct.tdfa.next_class(sid, k) {
+                dfa::DEAD => nstates,
+                target => target + off
+            }));
+            accept.push(ct.tdfa.accept(sid).map(|r| ct.rules[r] + 1).unwrap_or(0));
+        }
+    }
+    emit_automata(&starts, &transitions, &accept, classes.len(), out);
+
+    let nregs = tdfas.iter().map(|ct| ct.tdfa.num_registers()).max().unwrap_or(0);
+    let nslots = tdfas.iter().map(|ct| ct.tdfa.num_slots()).max().unwrap_or(2);
+    out.push_str(&format!("const NUM_REGISTERS: usize = {};\n", nregs));
+    out.push_str(&format!("const NUM_SLOTS: usize = {};\n\n", nslots));
+
+    // the operations of the transitions, then the ones of the start of
+    // each condition, as (register, source) pairs where the source
+    // NUM_REGISTERS stands for the current position
+    let op_value = |op: &RegOp| match *op {
+        RegOp::Set(dst) => format!("({}, {})", dst, nregs),
+        RegOp::Copy(dst, src) => format!("({}, {})", dst, src)
+    };
+    let mut ops = Vec::new();
+    let mut op_starts = Vec::with_capacity(transitions.len() + tdfas.len() + 1);
+    for ct in tdfas {
+        for sid in 0 .. ct.tdfa.num_states() {
+            for k in 0 .. classes.len() {
+                op_starts.push(ops.len());
+                ops.extend(ct.tdfa.ops(sid, k).iter().map(&op_value));
+            }
+        }
+    }
+    for ct in tdfas {
+        op_starts.push(ops.len());
+        ops.extend(ct.tdfa.start_ops().iter().map(&op_value));
+    }
+    op_starts.push(ops.len());
+
+    let reg_type = uint_type(nregs + 1);
+    out.push_str(&format!("const OPS: [({}, {}); {}] = [\n", reg_type, reg_type, ops.len()));
+    for line in ops.chunks(8) {
+        out.push_str(&format!("    {},\n", line.join(", ")));
+    }
+    out.push_str("];\n\n");
+    out.push_str(&format!("const OP_STARTS: [{}; {}] = [\n", uint_type(ops.len() + 1), op_starts.len()));
+    emit_values(&op_starts, 16, out);
+    out.push_str("];\n\n");
+
+    // the register of each slot in the accepting states, or NUM_REGISTERS
+    out.push_str(&format!("const FINAL_REGISTERS: [[{}; NUM_SLOTS]; {}] = [\n", reg_type, nstates));
+    for ct in tdfas {
+        for sid in 0 .. ct.tdfa.num_states() {
+            let regs = ct.tdfa.final_registers(sid);
+            let row: Vec<String> = (0 .. nslots)
+                .map(|i| regs.get(i).cloned().unwrap_or(None).unwrap_or(nregs).to_string()).collect();
+            out.push_str(&format!("    [{}],\n", row.join(", ")));
+        }
+    }
+    out.push_str("];\n\n");
+
+    out.push_str("fn run_ops(regs: &mut [Option<usize>; NUM_REGISTERS], i: usize, pos: usize) {
+    for &(dst, src) in OPS[OP_STARTS[i] as usize .. OP_STARTS[i + 1] as usize].iter() {
+        regs[dst as usize] = if src as usize == NUM_REGISTERS { Some(pos) } else { regs[src as usize] };
+    }
+}
+
+fn slots(state: usize, regs: &[Option<usize>; NUM_REGISTERS]) -> [Option<usize>; NUM_SLOTS] {
+    let mut res = [None; NUM_SLOTS];
+    for (slot, &r) in FINAL_REGISTERS[state].iter().enumerate() {
+        if (r as usize) < NUM_REGISTERS {
+            res[slot] = regs[r as usize];
+        }
+    }
+    res
+}
+
+");
+    let text_type = if options.utf8_bytes { "[u8]" } else { "str" };
+    out.push_str(&format!("// the text of group i of a match, if it took part in it
+fn group<'t>(text: &'t {0}, slots: &[Option<usize>; NUM_SLOTS], i: usize) -> Option<&'t {0}> {{
+    match (slots[2 * i], slots[2 * i + 1]) {{
+        (Some(start), Some(end)) => Some(&text[start .. end]),
+        _ => None
+    }}
+}}
+
+", text_type));
+}
+
+const DRIVER_CHARS: &str = "pub struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+    cond: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Lexer<'a> {
+        Lexer { input: input, pos: 0, cond: INITIAL }
+    }
+
+    // skips the character at the current position, which no rule matches
+    fn skip(&mut self) {
+        self.pos += self.input[self.pos..].chars().next().unwrap().len_utf8();
+    }
+
+";
+
+// the byte lexer reads the input as it is, without decoding it
+const DRIVER_BYTES: &str = "pub struct Lexer<'a> {
+    input: &'a [u8],
+    pos: usize,
+    cond: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a [u8]) -> Lexer<'a> {
+        Lexer { input: input, pos: 0, cond: INITIAL }
+    }
+
+    pub fn from_str(input: &'a str) -> Lexer<'a> {
+        Lexer::new(input.as_bytes())
+    }
+
+    // skips the byte at the current position, which no rule matches; the
+    // following bytes of its character, if any, cannot start a match
+    fn skip(&mut self) {
+        self.pos += 1;
+    }
+
+";
+
+const DRIVER_START: &str = "    /// Switches to start condition `cond`.
+    pub fn begin(&mut self, cond: usize) {
+        self.cond = cond;
+    }
+
+    /// Returns the current start condition.
+    pub fn condition(&self) -> usize {
+        self.cond
+    }
+
+";
+
+const SCAN_CHARS: &str = "    // longest match at the current position: (rule, length in bytes)
+    fn scan(&self) -> Option<(usize, usize)> {
+        let mut state = START_STATES[self.cond];
+        let mut last = accept(state).map(|r| (r, 0));
+        for (i, c) in self.input[self.pos..].char_indices() {
+            match transition(state, class_of(c)) {
+                Some(next) => {
+                    state = next;
+                    if let Some(r) = accept(state) {
+                        last = Some((r, i + c.len_utf8()));
+                    }
+                },
+                None => break
+            }
+        }
+        last
+    }
+
+";
+
+// the automata only accept whole UTF-8 sequences, so matches end at
+// character boundaries
+const SCAN_BYTES: &str = "    // longest match at the current position: (rule, length in bytes)
+    fn scan(&self) -> Option<(usize, usize)> {
+        let mut state = START_STATES[self.cond];
+        let mut last = accept(state).map(|r| (r, 0));
+        for (i, &b) in self.input[self.pos..].iter().enumerate() {
+            match transition(state, class_of(b)) {
+                Some(next) => {
+                    state = next;
+                    if let Some(r) = accept(state) {
+                        last = Some((r, i + 1));
+                    }
+                },
+                None => break
+            }
+        }
+        last
+    }
+
+";
+
+// with named groups, the registers of the tagged DFA are updated on each
+// transition, and the slots of the last match are kept
+const SCAN_CHARS_TAGGED: &str = "    // longest match at the current position: (rule, length in bytes,
+    // capture slots)
+    fn scan(&self) -> Option<(usize, usize, [Option<usize>; NUM_SLOTS])> {
+        let mut regs = [None; NUM_REGISTERS];
+        let mut state = START_STATES[self.cond];
+        run_ops(&mut regs, TRANSITIONS.len() + self.cond, 0);
+        let mut last = accept(state).map(|r| (r, 0, slots(state, &regs)));
+        for (i, c) in self.input[self.pos..].char_indices() {
+            let class = class_of(c);
+            match transition(state, class) {
+                Some(next) => {
+                    run_ops(&mut regs, state * NUM_CLASSES + class, i + c.len_utf8());
+                    state = next;
+                    if let Some(r) = accept(state) {
+                        last = Some((r, i + c.len_utf8(), slots(state, &regs)));
+                    }
+                },
+                None => break
+            }
+        }
+        last
+    }
+
+";
+
+const SCAN_BYTES_TAGGED: &str = "    // longest match at the current position: (rule, length in bytes,
+    // capture slots)
+    fn scan(&self) -> Option<(usize, usize, [Option<usize>; NUM_SLOTS])> {
+        let mut regs = [None; NUM_REGISTERS];
+        let mut state = START_STATES[self.cond];
+        run_ops(&mut regs, TRANSITIONS.len() + self.cond, 0);
+        let mut last = accept(state).map(|r| (r, 0, slots(state, &regs)));
+        for (i, &b) in self.input[self.pos..].iter().enumerate() {
+            let class = class_of(b);
+            match transition(state, class) {
+                Some(next) => {
+                    run_ops(&mut regs, state * NUM_CLASSES + class, i + 1);
+                    state = next;
+                    if let Some(r) = accept(state) {
+                        last = Some((r, i + 1, slots(state, &regs)));
+                    }
+                },
+                None => break
+            }
+        }
+        last
+    }
+
+";
+
+const NEXT_TOKEN_START: &str = "    pub fn next_token(&mut self) -> Option<Token> {
+        loop {
+            if self.pos >= self.input.len() {
+                match self.cond {
+";
+
+const DRIVER_MATCH: &str = "                    _ => ()
+                }
+                return None;
+            }
+
+            let (rule, len) = match self.scan() {
+                Some((rule, len)) if len > 0 => (rule, len),
+                // the default rule of flex, without the echo
+                _ => {
+                    self.skip();
+                    continue;
+                }
+            };
+            let input = self.input;
+            let text = &input[self.pos .. self.pos + len];
+            self.pos += len;
+
+            match rule {
+";
+
+const DRIVER_MATCH_TAGGED: &str = "                    _ => ()
+                }
+                return None;
+            }
+
+            let (rule, len, slots) = match self.scan() {
+                Some((rule, len, slots)) if len > 0 => (rule, len, slots),
+                // the default rule of flex, without the echo
+                _ => {
+                    self.skip();
+                    continue;
+                }
+            };
+            let input = self.input;
+            let text = &input[self.pos .. self.pos + len];
+            self.pos += len;
+
+            match rule {
+";
+
+const DRIVER_END: &str = "                _ => unreachable!()
+            }
+        }
+    }
+}
+";
+
+/// Options for the generation of lexers.
+#[derive(Default)]
+pub struct Options {
+    /// Scan the UTF-8 encoding of the input byte by byte, with tables of
+    /// 256 entries, instead of decoding it into characters.
+    pub utf8_bytes: bool,
+    /// Limits on the size of the DFA for each start condition.
+    pub limit: DfaLimit,
+    /// Construction of the NFAs that are determinized.
+    pub construction: Construction,
+}
+
+/// Generates the Rust source code of a lexer for `spec` with default options.
+pub fn generate(spec: &LexSpec) -> Result<String, Diagnostic> {
+    generate_with(spec, &Options::default())
+}
+
+/// Generates the Rust source code of a lexer for `spec`.
+///
+/// The generated code defines a constant for each start condition and a
+/// `Lexer` type whose `next_token` method returns the next `Token` from
+/// the input, where `Token` must be defined in the user code section of
+/// the specification. Rule actions are copied into `next_token` and can
+/// refer to the matched text as `text` and to the lexer as `self`; an
+/// action that does not return discards the match. When the input is
+/// exhausted, the end-of-file action of the current start condition runs
+/// on each call to `next_token`, and `None` is returned if it does not
+/// return by itself. Input that no rule matches is skipped one character
+/// at a time.
+///
+/// With `options.utf8_bytes`, `Lexer::new` takes a `&[u8]`, which is
+/// not validated as UTF-8 beforehand, and `text` is a `&[u8]`, while
+/// `Lexer::from_str` takes a `&str`. The rules only match the encodings
+/// of characters, so invalid UTF-8 is skipped like unmatched input.
+/// If some rule has named groups, as in `(?P<name>...)`, the lexer also
+/// records their positions while scanning, with a tagged DFA, and the
+/// action of such a rule can refer to the text of each of its named
+/// groups as an `Option<&str>` variable of the same name, or an
+/// `Option<&[u8]>` with `options.utf8_bytes`. Tagged DFAs are always
+/// built from Thompson NFAs, and are not minimized.
+///
+/// Fails if the DFA for a start condition exceeds `options.limit`.
+pub fn generate_with(spec: &LexSpec, options: &Options) -> Result<String, Diagnostic> {
+    let mut out = String::new();
+    out.push_str("// Generated by rslex; do not edit.\n\n");
+
+    for (id, cond) in spec.conditions.iter().enumerate() {
+        out.push_str(&format!("pub const {}: usize = {};\n", cond.name, id));
+    }
+    out.push('\n');
+
+    let tagged = spec.rules.iter().any(|r| !r.regex.group_names().is_empty());
+    if tagged {
+        let (tdfas, classes) = build_condition_tdfas(spec, options)?;
+        emit_tagged_tables(&tdfas, &classes, options, &mut out);
+    } else {
+        let dfas = (0 .. spec.conditions.len())
+            .map(|c| build_condition_dfa(spec, c, options)).collect::<Result<Vec<ConditionDFA>, Diagnostic>>()?;
+        emit_tables(&dfas, options, &mut out);
+    }
+
+    out.push_str(if options.utf8_bytes { DRIVER_BYTES } else { DRIVER_CHARS });
+    out.push_str(DRIVER_START);
+    out.push_str(match (tagged, options.utf8_bytes) {
+        (false, false) => SCAN_CHARS,
+        (false, true) => SCAN_BYTES,
+        (true, false) => SCAN_CHARS_TAGGED,
+        (true, true) => SCAN_BYTES_TAGGED
+    });
+    out.push_str(NEXT_TOKEN_START);
+    for cond in 0 .. spec.conditions.len() {
+        if let Some(action) = spec.eof_action(cond) {
+            out.push_str(&format!("                    {} => {{\n", spec.conditions[cond].name));
+            out.push_str(&indent(action, 6));
+            out.push_str("                    },\n");
+        }
+    }
+    out.push_str(if tagged { DRIVER_MATCH_TAGGED } else { DRIVER_MATCH });
+    for (id, rule) in spec.rules.iter().enumerate() {
+        out.push_str(&format!("                {} => {{\n", id));
+        for (i, name) in rule.regex.group_names() {
+            out.push_str("                    #[allow(unused_variables)]\n");
+            out.push_str(&format!("                    let {} = group(text, &slots, {});\n", name, i));
+        }
+        out.push_str(&indent(&rule.action, 5));
+        out.push_str("                },\n");
+    }
+    out.push_str(DRIVER_END);
+
+    if !spec.user_code.is_empty() {
+        out.push('\n');
+        out.push_str(&spec.user_code);
+    }
+
+    Ok(out)
+}
+
+#[test]
+fn test_generate() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%x STR
+%%
+"           { self.begin(STR); }
+<STR>"      { self.begin(INITIAL); return Some(Token::Str); }
+<STR>.
+a           return Some(Token::A);
+<<EOF>>     return None;
+<STR><<EOF>> {
+    panic!("unterminated string");
+}
+%%
+pub enum Token { A, Str }
+"#).unwrap();
+    let code = generate(&spec).unwrap();
+
+    assert!(code.contains("pub const INITIAL: usize = 0;\n"));
+    assert!(code.contains("pub const STR: usize = 1;\n"));
+    assert!(code.contains("const START_STATES: [usize; 2]"));
+    assert!(code.contains("const NUM_CLASSES: usize = 3;\n"));
+    assert!(code.contains("const CLASS_RANGES: [(char, char, usize); 0]"));
+    assert!(code.contains("                    STR => {\n                        {\n                            panic!(\"unterminated string\");\n                        }\n"));
+    assert!(code.contains("                    INITIAL => {\n                        return None;\n"));
+    assert!(code.contains("                3 => {\n                    return Some(Token::A);\n"));
+    assert!(code.ends_with("pub enum Token { A, Str }\n"));
+}
+
+#[test]
+fn test_generate_bytes() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%%
+[a-zé]+     return Some(Token::Word);
+.           return Some(Token::Other);
+"#).unwrap();
+    let code = generate_with(&spec, &Options { utf8_bytes: true, ..Options::default() }).unwrap();
+
+    assert!(code.contains("const BYTE_CLASSES: [u8; 256] = [\n"));
+    assert!(code.contains("    input: &'a [u8],\n"));
+    assert!(code.contains("    pub fn new(input: &'a [u8]) -> Lexer<'a> {\n"));
+    assert!(code.contains("for (i, &b) in self.input[self.pos..].iter().enumerate() {"));
+    assert!(!code.contains("CLASS_RANGES"));
+}
+
+#[test]
+fn test_generate_limit() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%x LONG
+%%
+if                      return Some(Token::If);
+<LONG>(a|b)*a(a|b){12}  return Some(Token::Long);
+"#).unwrap();
+    let options = Options { limit: DfaLimit { max_states: 1000, max_bytes: 1 << 30 }, ..Options::default() };
+    let err = generate_with(&spec, &options).err().unwrap();
+
+    assert_eq!(err.rule, 1);
+    assert_eq!(err.to_string(),
+               "error: rule 2 (`(a|b)*a(a|b){12}`) makes the DFA for start condition LONG exceed the limit of 1000 states");
+
+    // the DFAs built for complements are limited too
+    let spec = parse_spec(r#"
+%option set-operators
+%%
+if                          return Some(Token::If);
+[a-z]+&~((a|b)*a(a|b){12})  return Some(Token::Word);
+"#).unwrap();
+    let err = generate_with(&spec, &options).err().unwrap();
+    assert_eq!(err.rule, 1);
+}
+
+#[test]
+fn test_generate_named_groups() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%%
+(?P<int>[0-9]+)(\.(?P<frac>[0-9]+))?   return Some(Token::Num(int, frac));
+[a-z]+                                  return Some(Token::Word);
+"#).unwrap();
+    let code = generate(&spec).unwrap();
+
+    assert!(code.contains("const NUM_SLOTS: usize = 8;\n"));
+    assert!(code.contains("fn scan(&self) -> Option<(usize, usize, [Option<usize>; NUM_SLOTS])> {"));
+    assert!(code.contains("                0 => {\n                    #[allow(unused_variables)]\n                    let int = group(text, &slots, 1);\n"));
+    assert!(code.contains("                    let frac = group(text, &slots, 3);\n                    return Some(Token::Num(int, frac));\n"));
+    assert!(code.contains("                1 => {\n                    return Some(Token::Word);\n"));
+
+    // without named groups, the plain DFA is used
+    let spec = parse_spec("%%\n([0-9]+)   return Some(Token::Num);\n").unwrap();
+    assert!(!generate(&spec).unwrap().contains("NUM_REGISTERS"));
+}
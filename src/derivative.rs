@@ -0,0 +1,322 @@
+//
+// derivative.rs
+// DFA construction by Brzozowski derivatives
+//
+// The derivative of an expression r by a character c matches the strings
+// w such that r matches cw. Each state of the DFA is an expression, and
+// its transition by c leads to the derivative by c. Expressions are kept
+// in a normal form (the similarity rules: unions and intersections are
+// flattened, sorted and without duplicates, with the identities of the
+// empty language and of epsilon applied), which makes the number of
+// distinct derivatives finite.
+//
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use classes::CharClasses;
+use nfa::Label;
+use nfa::NFA;
+use nfa::NFABuilder;
+use nfa::Spec;
+use nfa::StateID;
+
+/// A regular expression with intersection and complement, in normal
+/// form when built by the functions of this module.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
+pub enum Re {
+    /// The empty language.
+    Empty,
+    Epsilon,
+    /// A character matched by a `Symbol`, `Class` or `Any` label.
+    Symbol(Label),
+    Concat(Rc<Re>, Rc<Re>),
+    /// Union of at least two expressions, in order.
+    Or(Vec<Rc<Re>>),
+    /// Intersection of at least two expressions, in order.
+    And(Vec<Rc<Re>>),
+    Not(Rc<Re>),
+    Star(Rc<Re>)
+}
+
+pub fn empty() -> Rc<Re> {
+    Rc::new(Re::Empty)
+}
+
+pub fn epsilon() -> Rc<Re> {
+    Rc::new(Re::Epsilon)
+}
+
+pub fn symbol(l: Label) -> Rc<Re> {
+    match l {
+        Label::Epsilon => epsilon(),
+        l => Rc::new(Re::Symbol(l))
+    }
+}
+
+pub fn concat(r1: Rc<Re>, r2: Rc<Re>) -> Rc<Re> {
+    match (&*r1, &*r2) {
+        (&Re::Empty, _) | (_, &Re::Empty) => return empty(),
+        (&Re::Epsilon, _) => return r2.clone(),
+        (_, &Re::Epsilon) => return r1.clone(),
+        // concatenations are nested to the right
+        (Re::Concat(a, b), _) => return concat(a.clone(), concat(b.clone(), r2.clone())),
+        _ => ()
+    }
+    Rc::new(Re::Concat(r1, r2))
+}
+
+// the operands of a union or intersection, flattened, in order and
+// without duplicates
+fn operands<F: Fn(&Re) -> Option<&Vec<Rc<Re>>>>(rs: Vec<Rc<Re>>, nested: F) -> Vec<Rc<Re>> {
+    let mut res = Vec::new();
+    for r in rs {
+        match nested(&r) {
+            Some(inner) => res.extend(inner.iter().cloned()),
+            None => res.push(r.clone())
+        }
+    }
+    res.sort();
+    res.dedup();
+    res
+}
+
+pub fn or(rs: Vec<Rc<Re>>) -> Rc<Re> {
+    let mut rs = operands(rs, |r| match *r { Re::Or(ref v) => Some(v), _ => None });
+    rs.retain(|r| **r != Re::Empty);
+    if rs.iter().any(|r| is_all(r)) {
+        return all();
+    }
+    match rs.len() {
+        0 => empty(),
+        1 => rs.pop().unwrap(),
+        _ => Rc::new(Re::Or(rs))
+    }
+}
+
+pub fn and(rs: Vec<Rc<Re>>) -> Rc<Re> {
+    let mut rs = operands(rs, |r| match *r { Re::And(ref v) => Some(v), _ => None });
+    if rs.iter().any(|r| **r == Re::Empty) {
+        return empty();
+    }
+    rs.retain(|r| !is_all(r));
+    match rs.len() {
+        0 => all(),
+        1 => rs.pop().unwrap(),
+        _ => Rc::new(Re::And(rs))
+    }
+}
+
+pub fn not(r: Rc<Re>) -> Rc<Re> {
+    if let Re::Not(ref inner) = *r {
+        return inner.clone();
+    }
+    Rc::new(Re::Not(r))
+}
+
+pub fn star(r: Rc<Re>) -> Rc<Re> {
+    match *r {
+        Re::Empty | Re::Epsilon => return epsilon(),
+        Re::Star(_) => return r.clone(),
+        _ => ()
+    }
+    Rc::new(Re::Star(r))
+}
+
+// the language of all strings
+fn all() -> Rc<Re> {
+    not(empty())
+}
+
+fn is_all(r: &Re) -> bool {
+    match *r {
+        Re::Not(ref inner) => **inner == Re::Empty,
+        _ => false
+    }
+}
+
+/// Translates a spec into an expression.
+pub fn from_spec(s: &Spec) -> Rc<Re> {
+    match *s {
+        Spec::Single(ref l) => symbol(l.clone()),
+        Spec::Union(ref s1, ref s2) => or(vec![from_spec(s1), from_spec(s2)]),
+        Spec::Concat(ref s1, ref s2) => concat(from_spec(s1), from_spec(s2)),
+        Spec::Star(ref s) => star(from_spec(s)),
+        Spec::Group(_, ref s) => from_spec(s),
+        Spec::Intersection(ref s1, ref s2) => and(vec![from_spec(s1), from_spec(s2)]),
+        Spec::Complement(ref s) => not(from_spec(s))
+    }
+}
+
+/// Whether `r` matches the empty string.
+pub fn nullable(r: &Re) -> bool {
+    match *r {
+        Re::Empty | Re::Symbol(_) => false,
+        Re::Epsilon | Re::Star(_) => true,
+        Re::Concat(ref r1, ref r2) => nullable(r1) && nullable(r2),
+        Re::Or(ref rs) => rs.iter().any(|r| nullable(r)),
+        Re::And(ref rs) => rs.iter().all(|r| nullable(r)),
+        Re::Not(ref r) => !nullable(r)
+    }
+}
+
+/// Returns the derivative of `r` by `c`.
+pub fn derivative(r: &Rc<Re>, c: char) -> Rc<Re> {
+    match **r {
+        Re::Empty | Re::Epsilon => empty(),
+        Re::Symbol(ref l) => if *l == Label::Any || l.matches(c) { epsilon() } else { empty() },
+        Re::Concat(ref r1, ref r2) => {
+            let d = concat(derivative(r1, c), r2.clone());
+            if nullable(r1) {
+                or(vec![d, derivative(r2, c)])
+            } else {
+                d
+            }
+        },
+        Re::Or(ref rs) => or(rs.iter().map(|r| derivative(r, c)).collect()),
+        Re::And(ref rs) => and(rs.iter().map(|r| derivative(r, c)).collect()),
+        Re::Not(ref r) => not(derivative(r, c)),
+        Re::Star(ref r1) => concat(derivative(r1, c), r.clone())
+    }
+}
+
+fn collect_labels<'a>(r: &'a Re, labels: &mut Vec<&'a Label>) {
+    match *r {
+        Re::Empty | Re::Epsilon => (),
+        Re::Symbol(ref l) => labels.push(l),
+        Re::Concat(ref r1, ref r2) => {
+            collect_labels(r1, labels);
+            collect_labels(r2, labels);
+        },
+        Re::Or(ref rs) | Re::And(ref rs) => for r in rs.iter() { collect_labels(r, labels) },
+        Re::Not(ref r) | Re::Star(ref r) => collect_labels(r, labels)
+    }
+}
+
+/// Builds the DFA of the expressions `rs`, as an automaton like the ones
+/// built by `NFA::to_dfa`: the accepting states are tagged with the
+/// lowest index of an expression they match.
+///
+/// Each state is the vector of the derivatives of all expressions by the
+/// same input, and the states where all of them are empty are left out.
+pub fn build_dfa(rs: &[Rc<Re>]) -> NFA {
+    let mut labels = Vec::new();
+    for r in rs.iter() {
+        collect_labels(r, &mut labels);
+    }
+    let classes = CharClasses::new(labels.into_iter());
+    let reps: Vec<Option<char>> = (0..classes.len()).map(|k| classes.representative(k)).collect();
+    // class 0 gets its ranges rather than Any, as transitions to the empty
+    // expression are left out, and Any would then take their characters
+    let class_labels: Vec<Label> = (0..classes.len())
+        .map(|k| if k == 0 { Label::Class(classes.ranges(0)) } else { classes.label(k) }).collect();
+
+    let mut builder = NFABuilder::new();
+    let mut states: Vec<Vec<Rc<Re>>> = Vec::new();
+    let mut state_map: HashMap<Vec<Rc<Re>>, StateID> = HashMap::new();
+    add_state(rs.to_vec(), &mut builder, &mut states, &mut state_map);
+
+    // states are numbered in the order they are found, so the states
+    // still to be processed are the ones after state
+    let mut state = 0;
+    while state < states.len() {
+        for k in 0..classes.len() {
+            let c = match reps[k] {
+                Some(c) => c,
+                None => continue
+            };
+            let ds: Vec<Rc<Re>> = states[state].iter().map(|r| derivative(r, c)).collect();
+            if ds.iter().all(|r| **r == Re::Empty) {
+                continue;
+            }
+            let target = match state_map.get(&ds) {
+                Some(&t) => t,
+                None => add_state(ds, &mut builder, &mut states, &mut state_map)
+            };
+            builder.add_transition(state, target, class_labels[k].clone());
+        }
+        state += 1;
+    }
+
+    builder.finish(0)
+}
+
+fn add_state(rs: Vec<Rc<Re>>, builder: &mut NFABuilder, states: &mut Vec<Vec<Rc<Re>>>,
+             state_map: &mut HashMap<Vec<Rc<Re>>, StateID>) -> StateID {
+    let state = builder.add_state(rs.iter().position(|r| nullable(r)));
+    states.push(rs.clone());
+    state_map.insert(rs, state);
+    state
+}
+
+#[test]
+fn test_similarity() {
+    let a = symbol(Label::Symbol('a'));
+    let b = symbol(Label::Symbol('b'));
+
+    assert_eq!(or(vec![a.clone(), b.clone()]), or(vec![b.clone(), or(vec![a.clone(), empty()]), a.clone()]));
+    assert_eq!(and(vec![a.clone(), all()]), a);
+    assert_eq!(and(vec![a.clone(), empty()]), empty());
+    assert_eq!(concat(epsilon(), concat(a.clone(), epsilon())), a);
+    assert_eq!(not(not(a.clone())), a);
+    assert_eq!(star(star(a.clone())), star(a.clone()));
+
+    // the derivatives of a*b by a are all a*b
+    let r = concat(star(a.clone()), b.clone());
+    assert_eq!(derivative(&r, 'a'), r);
+    assert_eq!(derivative(&r, 'b'), epsilon());
+    assert_eq!(derivative(&r, 'c'), empty());
+}
+
+#[test]
+fn test_derivative_dfa() {
+    use regex_syntax::Expr;
+    use rules;
+
+    let patterns = [r"if", r"[a-z][a-z0-9]*", r"[0-9]+(\.[0-9]+)?", r"(ab|a)*c", r"a.*z", r"."];
+    let specs: Vec<Spec> = patterns.iter().map(|p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap())).collect();
+    let res: Vec<Rc<Re>> = specs.iter().map(from_spec).collect();
+    let dfa = build_dfa(&res);
+    let nfa = NFABuilder::build_from_specs(&mut specs.clone());
+    let minimal = nfa.to_dfa().minimize();
+
+    assert!(dfa.num_states() <= nfa.to_dfa().num_states());
+    assert_eq!(dfa.minimize().num_states(), minimal.num_states());
+    for w in ["if", "iff", "x1", "3.14", "3.", "ababac", "abc", "c", "a!z", "az", "!", "é", ""].iter() {
+        assert_eq!(dfa.longest_match(w), nfa.longest_match(w), "{}", w);
+    }
+}
+
+#[test]
+fn test_intersection_complement() {
+    let a = symbol(Label::Symbol('a'));
+    let b = symbol(Label::Symbol('b'));
+    let any = star(symbol(Label::Any));
+
+    // strings of a and b with an even length, but not containing "bb"
+    let ab = or(vec![a.clone(), b.clone()]);
+    let even = star(concat(ab.clone(), ab.clone()));
+    let bb = concat(any.clone(), concat(concat(b.clone(), b.clone()), any.clone()));
+    let dfa = build_dfa(&[and(vec![even, not(bb)])]);
+
+    for &(w, accepted) in [("", true), ("ab", true), ("abab", true), ("abba", false), ("aba", false),
+                           ("ac", false), ("baab", true), ("bb", false)].iter() {
+        assert_eq!(dfa.simulate(w), accepted, "{}", w);
+    }
+
+    // the complement of a* accepts all strings with another character
+    let dfa = build_dfa(&[not(star(a.clone()))]);
+    assert!(dfa.simulate("b"));
+    assert!(dfa.simulate("aaé"));
+    assert!(!dfa.simulate("aaa"));
+    assert!(!dfa.simulate(""));
+
+    // the strings not starting with b, where the class of the characters
+    // other than b has no transition to the empty expression
+    let dfa = build_dfa(&[not(concat(b.clone(), not(empty())))]);
+    assert!(!dfa.simulate("b"));
+    assert!(!dfa.simulate("ba"));
+    assert!(dfa.simulate("ab"));
+    assert!(dfa.simulate("é"));
+    assert!(dfa.simulate(""));
+}
@@ -0,0 +1,194 @@
+//
+// dfa.rs
+// Table-driven Deterministic Finite Automata
+//
+
+use classes::CharClasses;
+use nfa::NFA;
+use nfa::StateID;
+use nfa::RuleID;
+
+/// Target of the transitions that lead to no state.
+pub const DEAD: StateID = usize::MAX;
+
+/// A DFA whose transitions are stored in a dense table, indexed by state
+/// and input equivalence class.
+pub struct DFA {
+    classes: CharClasses,
+    // transitions of state s are at s * classes.len() .. (s + 1) * classes.len()
+    table: Vec<StateID>,
+    accept: Vec<Option<RuleID>>,
+    start: StateID
+}
+
+impl DFA {
+    /// Builds the transition table of `dfa`, an automaton built by
+    /// `NFA::to_dfa`, indexed by its own input classes.
+    pub fn new(dfa: &NFA) -> DFA {
+        DFA::with_classes(dfa, dfa.char_classes())
+    }
+
+    /// Builds the transition table of `dfa` indexed by `classes`, which
+    /// must not put together characters that `dfa` tells apart. This
+    /// allows several automata to share their classes.
+    pub fn with_classes(dfa: &NFA, classes: CharClasses) -> DFA {
+        let reps: Vec<Option<char>> = (0..classes.len()).map(|k| classes.representative(k)).collect();
+        let mut table = Vec::with_capacity(dfa.num_states() * classes.len());
+        let mut accept = Vec::with_capacity(dfa.num_states());
+
+        for sid in 0..dfa.num_states() {
+            for rep in reps.iter() {
+                let target = match *rep {
+                    Some(c) => dfa.step_deterministic(sid, c).unwrap_or(DEAD),
+                    None => DEAD
+                };
+                table.push(target);
+            }
+            accept.push(dfa.get_state(sid).unwrap().rule());
+        }
+
+        DFA { classes, table, accept, start: dfa.start_state() }
+    }
+
+    pub fn classes(&self) -> &CharClasses {
+        &self.classes
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    pub fn start_state(&self) -> StateID {
+        self.start
+    }
+
+    /// Returns the state reached from `s` by a character of class `k`,
+    /// or `DEAD`.
+    pub fn next_class(&self, s: StateID, k: usize) -> StateID {
+        self.table[s * self.classes.len() + k]
+    }
+
+    pub fn next(&self, s: StateID, c: char) -> StateID {
+        self.next_class(s, self.classes.class_of(c))
+    }
+
+    /// Returns the rule recognized in state `s`, if it is accepting.
+    pub fn accept(&self, s: StateID) -> Option<RuleID> {
+        self.accept[s]
+    }
+
+    pub fn simulate(&self, word: &str) -> bool {
+        let mut s = self.start;
+        for c in word.chars() {
+            s = self.next(s, c);
+            if s == DEAD {
+                return false;
+            }
+        }
+        self.accept[s].is_some()
+    }
+
+    /// Finds the longest prefix of `input` recognized by the automaton,
+    /// like `NFA::longest_match`.
+    pub fn longest_match(&self, input: &str) -> Option<(RuleID, usize)> {
+        let mut s = self.start;
+        let mut last = self.accept[s].map(|r| (r, 0));
+
+        for (i, c) in input.char_indices() {
+            s = self.next(s, c);
+            if s == DEAD {
+                break;
+            }
+            if let Some(r) = self.accept[s] {
+                last = Some((r, i + c.len_utf8()));
+            }
+        }
+
+        last
+    }
+
+    /// Finds the longest prefix of `input` recognized by a byte-level
+    /// automaton (see the `utf8` module), with its length in bytes.
+    pub fn longest_match_bytes(&self, input: &[u8]) -> Option<(RuleID, usize)> {
+        let mut s = self.start;
+        let mut last = self.accept[s].map(|r| (r, 0));
+
+        for (i, &b) in input.iter().enumerate() {
+            s = self.next(s, b as char);
+            if s == DEAD {
+                break;
+            }
+            if let Some(r) = self.accept[s] {
+                last = Some((r, i + 1));
+            }
+        }
+
+        last
+    }
+
+    /// Returns the size of the transition and accept tables in bytes,
+    /// when stored with the smallest unsigned type that fits their values.
+    pub fn table_bytes(&self) -> usize {
+        // both tables need an extra value: a dead state, or no rule
+        let nrules = self.accept.iter().filter_map(|&r| r).max().map(|r| r + 1).unwrap_or(0);
+        self.table.len() * entry_size(self.num_states() + 1) + self.accept.len() * entry_size(nrules + 1)
+    }
+}
+
+/// Returns the number of bytes needed to store a value in `0..n`.
+pub fn entry_size(n: usize) -> usize {
+    if n <= 1 << 8 {
+        1
+    } else if n <= 1 << 16 {
+        2
+    } else {
+        4
+    }
+}
+
+#[test]
+fn test_dfa_table() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let mut specs = vec![rules::regex_to_nfa_spec(&Expr::parse(r"if").unwrap()),
+                         rules::regex_to_nfa_spec(&Expr::parse(r"[a-z][a-z0-9]*").unwrap()),
+                         rules::regex_to_nfa_spec(&Expr::parse(r"[0-9]+").unwrap())];
+    let dfa = NFABuilder::build_from_specs(&mut specs).to_dfa().minimize();
+    let table = DFA::new(&dfa);
+
+    // others, [0-9], f, i, and the other letters
+    assert_eq!(table.classes().len(), 5);
+    assert_eq!(table.classes().class_of('a'), table.classes().class_of('z'));
+    assert_eq!(table.num_states(), dfa.num_states());
+    assert_eq!(table.next(table.start_state(), '!'), DEAD);
+
+    for w in ["if", "iff", "i", "x9", "42", "4x", "", "!"].iter() {
+        assert_eq!(table.longest_match(w), dfa.longest_match(w));
+        assert_eq!(table.simulate(w), dfa.simulate(w));
+    }
+    assert_eq!(table.longest_match("if("), Some((0, 2)));
+    assert_eq!(table.longest_match("ifx"), Some((1, 3)));
+    assert_eq!(table.longest_match("123a"), Some((2, 3)));
+}
+
+#[test]
+fn test_dfa_bytes() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%%
+if                  a
+[a-zé∀][a-z0-9é]*   b
+[^a-z]              c
+.                   d
+"#).unwrap();
+    let chars = DFA::new(&spec.condition_nfa(0).to_dfa().minimize());
+    let bytes = DFA::new(&spec.condition_utf8_nfa(0).to_dfa().minimize());
+
+    assert!(bytes.classes().len() <= 256);
+    for w in ["if", "iffé", "é1 x", "∀x", "∃x", "😀", "\u{10FFFF}", "ab\u{7F}", ""].iter() {
+        assert_eq!(bytes.longest_match_bytes(w.as_bytes()), chars.longest_match(w), "{:?}", w);
+    }
+}
@@ -0,0 +1,316 @@
+//
+// diagnostics.rs
+// Static checks on lexer specifications
+//
+
+use std::fmt;
+
+use arena::SpecArena;
+use nfa::Construction;
+use nfa::DfaLimit;
+use nfa::NFA;
+use nfa::NFABuilder;
+use nfa::RuleID;
+use nfa::StateExplosion;
+use parser::LexSpec;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    /// The lexer can be generated, but probably does not do what was intended.
+    Warning,
+    /// No lexer should be generated for the specification.
+    Error
+}
+
+/// A problem found in a rule of a specification.
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub rule: RuleID,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message)
+        }
+    }
+}
+
+fn describe(spec: &LexSpec, rule: RuleID) -> String {
+    format!("rule {} (`{}`)", rule + 1, spec.rules[rule].pattern)
+}
+
+/// Finds the rules that can never be matched.
+///
+/// A rule is shadowed when every string it accepts is also accepted by
+/// an earlier rule, so that the earlier rule always wins, in all start
+/// conditions where the rule is active. The warning for such a rule
+/// gives a shortest string it accepts and the rule that takes it.
+pub fn shadowed_rules(spec: &LexSpec) -> Vec<Diagnostic> {
+    let mut wins = vec![false; spec.rules.len()];
+    let mut examples: Vec<Option<(String, RuleID)>> = vec![None; spec.rules.len()];
+
+    for cond in 0 .. spec.conditions.len() {
+        let active = spec.active_rules(cond);
+        let usage = spec.condition_nfa(cond).rule_usage(active.len());
+        for (i, u) in usage.into_iter().enumerate() {
+            let rule = active[i];
+            wins[rule] = wins[rule] || u.wins;
+            if examples[rule].is_none() {
+                examples[rule] = u.example.map(|(word, winner)| (word, active[winner]));
+            }
+        }
+    }
+
+    let mut res = Vec::new();
+    for rule in 0 .. spec.rules.len() {
+        if wins[rule] {
+            continue;
+        }
+        if let Some((ref word, winner)) = examples[rule] {
+            let message = format!("{} can never be matched: {:?} is matched by {}",
+                                  describe(spec, rule), word, describe(spec, winner));
+            res.push(Diagnostic { severity: Severity::Warning, rule, message });
+        }
+    }
+    res
+}
+
+/// Finds the rules that match the empty string, given the NFA of each
+/// rule of `spec`, as built by `rule_nfas`.
+///
+/// A lexer that takes the longest match at each position would loop
+/// forever on such a rule, so each one is reported as an error.
+pub fn nullable_rules(spec: &LexSpec, nfas: &[NFA]) -> Vec<Diagnostic> {
+    let mut res = Vec::new();
+    for (rule, nfa) in nfas.iter().enumerate() {
+        if nfa.accepts_empty() {
+            let message = format!("{} matches the empty string", describe(spec, rule));
+            res.push(Diagnostic { severity: Severity::Error, rule, message });
+        }
+    }
+    res
+}
+
+/// Finds the rules that match no string at all, which can only happen
+/// with the intersection and complement operators, given the NFA of
+/// each rule of `spec`, as built by `rule_nfas`.
+pub fn empty_rules(spec: &LexSpec, nfas: &[NFA]) -> Vec<Diagnostic> {
+    let mut res = Vec::new();
+    for (rule, nfa) in nfas.iter().enumerate() {
+        if nfa.is_empty() {
+            let message = format!("{} does not match any string", describe(spec, rule));
+            res.push(Diagnostic { severity: Severity::Warning, rule, message });
+        }
+    }
+    res
+}
+
+/// Describes the failure to build the DFA for start condition `cond`
+/// within `limit`, as an error on the rule responsible for it.
+pub fn state_explosion(spec: &LexSpec, cond: usize, e: &StateExplosion, limit: &DfaLimit) -> Diagnostic {
+    let active = spec.active_rules(cond);
+    let rule = active[e.rule.unwrap_or(0)];
+    let message = format!("{} makes the DFA for start condition {} exceed the limit of {}",
+                          describe(spec, rule), spec.conditions[cond].name, exceeded(e, limit));
+    Diagnostic { severity: Severity::Error, rule, message }
+}
+
+// the limit that e exceeds
+fn exceeded(e: &StateExplosion, limit: &DfaLimit) -> String {
+    if e.states > limit.max_states {
+        format!("{} states", limit.max_states)
+    } else {
+        format!("{} bytes", limit.max_bytes)
+    }
+}
+
+// the error for a rule whose automata exceed limit
+fn rule_explosion(spec: &LexSpec, rule: RuleID, e: &StateExplosion, limit: &DfaLimit) -> Diagnostic {
+    let message = format!("{} makes its DFA exceed the limit of {}", describe(spec, rule), exceeded(e, limit));
+    Diagnostic { severity: Severity::Error, rule, message }
+}
+
+/// Builds the NFA of each rule of `spec` on its own.
+///
+/// Fails if the DFA built for an intersection or a complement in a rule
+/// exceeds `limit`.
+pub fn rule_nfas(spec: &LexSpec, limit: &DfaLimit) -> Result<Vec<NFA>, Diagnostic> {
+    (0 .. spec.rules.len()).map(|rule| {
+        let mut arena = SpecArena::new();
+        let id = spec.rules[rule].regex.to_spec_id(&mut arena);
+        NFABuilder::build_from_arena_limited(&arena, &[id], limit)
+            .map_err(|e| rule_explosion(spec, rule, &e, limit))
+    }).collect()
+}
+
+/// Two rules that accept a common string.
+pub struct Overlap {
+    pub first: RuleID,
+    pub second: RuleID,
+    /// A shortest string accepted by both rules.
+    pub witness: String,
+    /// The rule that matches `witness` in the lexer, by priority. This
+    /// is not necessarily one of the pair, as a third, earlier rule can
+    /// also accept the string.
+    pub winner: RuleID,
+}
+
+/// Finds all pairs of rules whose languages intersect.
+///
+/// Only pairs of rules active in a common start condition are compared;
+/// the winner is decided in the first such condition. The intersection
+/// is checked on the product of the DFAs of the two rules.
+///
+/// Fails if the DFA of a rule, or of an intersection or complement in a
+/// start condition, exceeds `limit`.
+pub fn overlaps(spec: &LexSpec, limit: &DfaLimit) -> Result<Vec<Overlap>, Diagnostic> {
+    let dfas = rule_nfas(spec, limit)?.iter().enumerate().map(|(rule, nfa)| {
+        nfa.to_dfa_limited(limit).map_err(|e| rule_explosion(spec, rule, &e, limit))
+    }).collect::<Result<Vec<NFA>, Diagnostic>>()?;
+    let nfas = (0 .. spec.conditions.len()).map(|c| {
+        spec.condition_nfa_limited(c, false, Construction::Thompson, limit)
+            .map_err(|e| state_explosion(spec, c, &e, limit))
+    }).collect::<Result<Vec<NFA>, Diagnostic>>()?;
+    let active: Vec<Vec<RuleID>> = (0 .. spec.conditions.len()).map(|c| spec.active_rules(c)).collect();
+
+    let mut res = Vec::new();
+    for first in 0 .. spec.rules.len() {
+        for second in first + 1 .. spec.rules.len() {
+            let cond = match active.iter().position(|a| a.contains(&first) && a.contains(&second)) {
+                Some(c) => c,
+                None => continue
+            };
+            if let Some(witness) = dfas[first].shortest_common(&dfas[second]) {
+                // the witness is accepted, so its longest match is the whole string
+                let (winner, _) = nfas[cond].longest_match(&witness).unwrap();
+                res.push(Overlap { first, second, witness, winner: active[cond][winner] });
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Formats the overlaps between the rules of `spec`, one per line.
+pub fn overlap_report(spec: &LexSpec, limit: &DfaLimit) -> Result<String, Diagnostic> {
+    let mut res = String::new();
+    for o in overlaps(spec, limit)? {
+        res.push_str(&format!("{} and {} both match {:?}, which is taken by rule {}\n",
+                              describe(spec, o.first), describe(spec, o.second), o.witness, o.winner + 1));
+    }
+    Ok(res)
+}
+
+/// Runs all checks on `spec`, returning the problems found.
+///
+/// Fails if the DFA built for an intersection or a complement in a rule
+/// exceeds `limit`.
+pub fn check(spec: &LexSpec, limit: &DfaLimit) -> Result<Vec<Diagnostic>, Diagnostic> {
+    let nfas = rule_nfas(spec, limit)?;
+    let mut res = nullable_rules(spec, &nfas);
+    res.extend(empty_rules(spec, &nfas));
+    res.extend(shadowed_rules(spec));
+    Ok(res)
+}
+
+/// Returns whether any of `diags` is an error.
+pub fn has_errors(diags: &[Diagnostic]) -> bool {
+    diags.iter().any(|d| d.severity == Severity::Error)
+}
+
+#[test]
+fn test_shadowed_rules() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%x STR
+%%
+(i|f)+      return Some(Token::Id);
+if          return Some(Token::If);
+<STR>if     return Some(Token::If);
+else        return Some(Token::Else);
+<*>e|else   return Some(Token::E);
+"#).unwrap();
+    let ws = check(&spec, &DfaLimit::default()).unwrap();
+
+    assert_eq!(ws.len(), 1);
+    assert_eq!(ws[0].rule, 1);
+    assert_eq!(ws[0].severity, Severity::Warning);
+    assert_eq!(ws[0].message, "rule 2 (`if`) can never be matched: \"if\" is matched by rule 1 (`(i|f)+`)");
+}
+
+#[test]
+fn test_nullable_rules() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%%
+a+      return Some(Token::A);
+b*      return Some(Token::B);
+(c|d)?  return Some(Token::C);
+d?e     return Some(Token::D);
+"#).unwrap();
+    let ds = check(&spec, &DfaLimit::default()).unwrap();
+
+    assert!(has_errors(&ds));
+    let errors: Vec<&Diagnostic> = ds.iter().filter(|d| d.severity == Severity::Error).collect();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].message, "rule 2 (`b*`) matches the empty string");
+    assert_eq!(errors[1].rule, 2);
+    assert_eq!(format!("{}", errors[1]), "error: rule 3 (`(c|d)?`) matches the empty string");
+}
+
+#[test]
+fn test_empty_rules() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%option set-operators
+%%
+[a-z]+&[0-9]+   return Some(Token::None);
+[a-z]+&~if      return Some(Token::Id);
+~(a|b)&a        return Some(Token::A);
+"#).unwrap();
+    let ds = check(&spec, &DfaLimit::default()).unwrap();
+
+    assert_eq!(ds.len(), 2);
+    assert_eq!(ds[0].message, "rule 1 (`[a-z]+&[0-9]+`) does not match any string");
+    assert_eq!((ds[1].rule, ds[1].severity), (2, Severity::Warning));
+}
+
+#[test]
+fn test_overlaps() {
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%x STR
+%%
+if          return Some(Token::If);
+(i|f)+      return Some(Token::Id);
+f(o|i)      return Some(Token::Fo);
+<STR>o+     return Some(Token::O);
+o           return Some(Token::Other);
+"#).unwrap();
+    let os = overlaps(&spec, &DfaLimit::default()).unwrap();
+
+    // rules 4 and 5 are never active together
+    assert_eq!(os.len(), 2);
+    assert_eq!((os[0].first, os[0].second, &os[0].witness[..], os[0].winner), (0, 1, "if", 0));
+    assert_eq!((os[1].first, os[1].second, &os[1].witness[..], os[1].winner), (1, 2, "fi", 1));
+
+    let report = overlap_report(&spec, &DfaLimit::default()).unwrap();
+    assert!(report.starts_with("rule 1 (`if`) and rule 2 (`(i|f)+`) both match \"if\", which is taken by rule 1\n"));
+    assert_eq!(report.lines().count(), 2);
+
+    let spec = parse_spec(r#"
+%%
+if                  return Some(Token::If);
+(a|b)*a(a|b){12}    return Some(Token::Long);
+"#).unwrap();
+    let limit = DfaLimit { max_states: 1000, max_bytes: 1 << 30 };
+    let err = overlaps(&spec, &limit).err().unwrap();
+    assert_eq!(err.to_string(), "error: rule 2 (`(a|b)*a(a|b){12}`) makes its DFA exceed the limit of 1000 states");
+}
@@ -0,0 +1,232 @@
+//
+// equivalence.rs
+// Language equivalence and inclusion of automata
+//
+// Both checks explore the subset automata of the two operands together,
+// one input class at a time, in order of input length. Equivalence uses
+// the algorithm of Hopcroft and Karp: the pairs of sets found are merged
+// in a union-find structure, and a pair already in the same class is not
+// explored again, since the sets were already found to agree if the
+// automata are equivalent.
+//
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+
+use classes::CharClasses;
+use nfa::NFA;
+use nfa::RuleID;
+use nfa::StateID;
+use nfa::sorted_states;
+use stateset::StateSet;
+
+/// A string on which two automata disagree, with the rule recognized by
+/// each of them, if any.
+#[derive(Debug, PartialEq)]
+pub struct Witness {
+    pub word: String,
+    pub first: Option<RuleID>,
+    pub second: Option<RuleID>
+}
+
+// the sets of states of an automaton reached by the inputs explored,
+// numbered in the order they are found
+struct Subsets<'a> {
+    nfa: &'a NFA,
+    sets: Vec<Vec<StateID>>,
+    ids: HashMap<Vec<StateID>, usize>,
+    cur: StateSet,
+    next: StateSet
+}
+
+impl<'a> Subsets<'a> {
+    fn new(nfa: &'a NFA) -> Subsets<'a> {
+        let mut subsets = Subsets { nfa, sets: Vec::new(), ids: HashMap::new(),
+                                    cur: StateSet::new(nfa.num_states()), next: StateSet::new(nfa.num_states()) };
+        let start = sorted_states(&nfa.start_set());
+        subsets.id(start);
+        subsets
+    }
+
+    fn id(&mut self, set: Vec<StateID>) -> usize {
+        if let Some(&i) = self.ids.get(&set) {
+            return i;
+        }
+        let i = self.sets.len();
+        self.sets.push(set.clone());
+        self.ids.insert(set, i);
+        i
+    }
+
+    // the set reached from set i by c
+    fn step(&mut self, i: usize, c: char) -> usize {
+        self.cur.clear();
+        for &s in self.sets[i].iter() {
+            self.cur.insert(s);
+        }
+        self.nfa.step_set(&self.cur, c, &mut self.next);
+        let set = sorted_states(&self.next);
+        self.id(set)
+    }
+
+    fn rule(&self, i: usize) -> Option<RuleID> {
+        self.sets[i].iter().filter_map(|&s| self.nfa.get_state(s).unwrap().rule()).min()
+    }
+}
+
+// a character of each class of input that the transitions of a or b can
+// tell apart
+fn representatives(a: &NFA, b: &NFA) -> Vec<char> {
+    let mut labels = Vec::new();
+    for nfa in [a, b].iter() {
+        for s in 0..nfa.num_states() {
+            labels.extend(nfa.get_state(s).unwrap().transitions().iter().map(|t| t.label().clone()));
+        }
+    }
+    let classes = CharClasses::new(labels.iter());
+    (0..classes.len()).filter_map(|k| classes.representative(k)).collect()
+}
+
+fn find(parent: &mut [usize], x: usize) -> usize {
+    let mut root = x;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    // path compression
+    let mut x = x;
+    while parent[x] != root {
+        let next = parent[x];
+        parent[x] = root;
+        x = next;
+    }
+    root
+}
+
+/// Checks that `a` and `b` recognize the same rule for every string (or
+/// both reject it), so that a lexer built from either one splits any
+/// input into the same tokens.
+///
+/// The automata need not be deterministic. When they differ, the error
+/// is a string on which they do; the search is breadth-first, so it is
+/// short, but not always shortest.
+pub fn equivalent(a: &NFA, b: &NFA) -> Result<(), Witness> {
+    let reps = representatives(a, b);
+    let mut sa = Subsets::new(a);
+    let mut sb = Subsets::new(b);
+
+    // the union-find forest over the sets of both automata, with set i
+    // of a as node 2 * i and set j of b as node 2 * j + 1, where the
+    // start sets are merged
+    let mut parent = vec![1, 1];
+    let mut queue = VecDeque::new();
+    queue.push_back((0, 0, String::new()));
+
+    while let Some((p, q, word)) = queue.pop_front() {
+        let (rp, rq) = (sa.rule(p), sb.rule(q));
+        if rp != rq {
+            return Err(Witness { word, first: rp, second: rq });
+        }
+
+        for &c in reps.iter() {
+            let (p2, q2) = (sa.step(p, c), sb.step(q, c));
+            let (x, y) = (2 * p2, 2 * q2 + 1);
+            let len = ::std::cmp::max(x, y) + 1;
+            while parent.len() < len {
+                let n = parent.len();
+                parent.push(n);
+            }
+            let (rx, ry) = (find(&mut parent, x), find(&mut parent, y));
+            if rx != ry {
+                parent[rx] = ry;
+                let mut new_word = word.clone();
+                new_word.push(c);
+                queue.push_back((p2, q2, new_word));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that every string accepted by `b` is also accepted by `a`,
+/// whatever the rules recognized.
+///
+/// The automata need not be deterministic. When the inclusion does not
+/// hold, the error is a shortest string accepted by `b` only.
+pub fn includes(a: &NFA, b: &NFA) -> Result<(), Witness> {
+    let reps = representatives(a, b);
+    let mut sa = Subsets::new(a);
+    let mut sb = Subsets::new(b);
+
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    seen.insert((0, 0));
+    queue.push_back((0, 0, String::new()));
+
+    while let Some((p, q, word)) = queue.pop_front() {
+        let (rp, rq) = (sa.rule(p), sb.rule(q));
+        if rq.is_some() && rp.is_none() {
+            return Err(Witness { word, first: rp, second: rq });
+        }
+
+        for &c in reps.iter() {
+            let (p2, q2) = (sa.step(p, c), sb.step(q, c));
+            // nothing is accepted by b after an empty set
+            if !sb.sets[q2].is_empty() && seen.insert((p2, q2)) {
+                let mut new_word = word.clone();
+                new_word.push(c);
+                queue.push_back((p2, q2, new_word));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_equivalent() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p: &str| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let nfa = |ps: &[&str]| NFABuilder::build_from_specs(&mut ps.iter().map(|p| spec(p)).collect());
+
+    let a = nfa(&[r"(a|b)*abb"]);
+    assert_eq!(equivalent(&a, &a.to_dfa()), Ok(()));
+    assert_eq!(equivalent(&a.to_dfa().minimize(), &a), Ok(()));
+    assert_eq!(equivalent(&a, &nfa(&[r"(a*b*)*ab(b)"])), Ok(()));
+    assert_eq!(equivalent(&a, &nfa(&[r"(a|b)*ab+"])),
+               Err(Witness { word: "ab".to_string(), first: None, second: Some(0) }));
+
+    // the rules recognized must agree too
+    let lexer = nfa(&[r"if", r"[a-z]+"]);
+    assert_eq!(equivalent(&lexer, &nfa(&[r"if", r"[a-z][a-z]*"])), Ok(()));
+    assert_eq!(equivalent(&lexer, &nfa(&[r"if|x", r"[a-z]+"])),
+               Err(Witness { word: "x".to_string(), first: Some(1), second: Some(0) }));
+
+    // characters no label names are told apart from the others
+    assert_eq!(equivalent(&nfa(&[r"(?s)."]), &nfa(&[r"[^a]|a"])), Ok(()));
+    let w = equivalent(&nfa(&[r"[a-z]|."]), &nfa(&[r"[a-z]"])).unwrap_err();
+    assert!(w.word.chars().count() == 1 && !w.word.chars().all(|c| c.is_lowercase()), "{:?}", w);
+}
+
+#[test]
+fn test_includes() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let nfa = |p| NFABuilder::build_from_spec(rules::regex_to_nfa_spec(&Expr::parse(p).unwrap()));
+    let ident = nfa(r"[a-z][a-z0-9]*");
+    let keywords = nfa(r"if|else|while");
+
+    assert_eq!(includes(&ident, &keywords), Ok(()));
+    assert_eq!(includes(&keywords, &ident),
+               Err(Witness { word: "a".to_string(), first: None, second: Some(0) }));
+    assert_eq!(includes(&ident, &ident.to_dfa()), Ok(()));
+    assert_eq!(includes(&nfa(r"(ab)*"), &nfa(r"(abab)*")), Ok(()));
+    assert_eq!(includes(&nfa(r"(abab)*"), &nfa(r"(ab)*")).unwrap_err().word, "ab");
+    assert_eq!(includes(&ident, &nfa(r"x\d+|é")).unwrap_err().word, "é");
+}
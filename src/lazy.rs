@@ -0,0 +1,275 @@
+//
+// lazy.rs
+// Lazy DFA: determinization on demand
+//
+// The states of the DFA are built during matching, as sets of NFA states,
+// only when the input reaches them. They are kept in a cache of bounded
+// size, which is flushed when it fills up. If the cache keeps filling up
+// within a single match, the rest of the match is done by simulating the
+// NFA instead.
+//
+
+use std::collections::HashMap;
+
+use classes::CharClasses;
+use dfa::DEAD;
+use nfa::NFA;
+use nfa::RuleID;
+use nfa::StateID;
+use nfa::sorted_states;
+use stateset::StateSet;
+
+/// Number of DFA states kept by `LazyDFA::new`.
+pub const DEFAULT_CACHE_STATES: usize = 1024;
+
+// target of the transitions not computed yet
+const UNKNOWN: StateID = DEAD - 1;
+
+/// A DFA whose states are built on demand from an NFA.
+///
+/// It has the matching methods of `dfa::DFA`, but they take `&mut self`,
+/// as they extend the cache.
+pub struct LazyDFA<'a> {
+    nfa: &'a NFA,
+    classes: CharClasses,
+    reps: Vec<Option<char>>,
+    capacity: usize,
+    // the sets of NFA states, as sorted vectors, for each cached state;
+    // the start state is always state 0
+    sets: Vec<Vec<StateID>>,
+    state_map: HashMap<Vec<StateID>, StateID>,
+    // transitions of state s are at s * classes.len() .. (s + 1) * classes.len()
+    table: Vec<StateID>,
+    accept: Vec<Option<RuleID>>,
+    flushes: usize,
+    // characters read since the last flush
+    since_flush: usize,
+    // set when the cache is flushed again after fewer characters than
+    // the states it holds; cleared at the start of each match
+    thrashing: bool,
+    // scratch sets for the steps
+    cur: StateSet,
+    next: StateSet
+}
+
+impl<'a> LazyDFA<'a> {
+    pub fn new(nfa: &'a NFA) -> LazyDFA<'a> {
+        LazyDFA::with_capacity(nfa, DEFAULT_CACHE_STATES)
+    }
+
+    /// Creates a lazy DFA that keeps at most `capacity` states, which
+    /// must be at least 2.
+    pub fn with_capacity(nfa: &'a NFA, capacity: usize) -> LazyDFA<'a> {
+        assert!(capacity >= 2, "a lazy DFA needs room for at least 2 states");
+        let classes = nfa.char_classes();
+        let reps = (0..classes.len()).map(|k| classes.representative(k)).collect();
+        let mut lazy = LazyDFA {
+            nfa,
+            classes,
+            reps,
+            capacity,
+            sets: Vec::new(),
+            state_map: HashMap::new(),
+            table: Vec::new(),
+            accept: Vec::new(),
+            flushes: 0,
+            since_flush: 0,
+            thrashing: false,
+            cur: StateSet::new(nfa.num_states()),
+            next: StateSet::new(nfa.num_states())
+        };
+        let start = sorted_states(&nfa.start_set());
+        lazy.add_state(start);
+        lazy
+    }
+
+    /// Returns the number of states in the cache.
+    pub fn num_cached_states(&self) -> usize {
+        self.sets.len()
+    }
+
+    /// Returns how many times the cache has been flushed.
+    pub fn cache_flushes(&self) -> usize {
+        self.flushes
+    }
+
+    pub fn simulate(&mut self, word: &str) -> bool {
+        // a word is accepted if and only if it is its own longest match
+        match self.longest_match(word) {
+            Some((_, len)) => len == word.len(),
+            None => false
+        }
+    }
+
+    /// Finds the longest prefix of `input` recognized by the automaton,
+    /// like `NFA::longest_match`.
+    pub fn longest_match(&mut self, input: &str) -> Option<(RuleID, usize)> {
+        self.search(input.char_indices().map(|(i, c)| (i + c.len_utf8(), c)))
+    }
+
+    /// Finds the longest prefix of `input` recognized by a byte-level
+    /// automaton (see the `utf8` module), with its length in bytes.
+    pub fn longest_match_bytes(&mut self, input: &[u8]) -> Option<(RuleID, usize)> {
+        self.search(input.iter().enumerate().map(|(i, &b)| (i + 1, b as char)))
+    }
+
+    // longest match over the characters of the input, each given with
+    // the position right after it
+    fn search<I: Iterator<Item=(usize, char)>>(&mut self, mut input: I) -> Option<(RuleID, usize)> {
+        self.thrashing = false;
+        let mut s = 0;
+        let mut last = self.accept[s].map(|r| (r, 0));
+
+        while let Some((end, c)) = input.next() {
+            s = self.next_state(s, c);
+            if s == DEAD {
+                return last;
+            }
+            if let Some(r) = self.accept[s] {
+                last = Some((r, end));
+            }
+            if self.thrashing {
+                return self.search_nfa(s, input, last);
+            }
+        }
+
+        last
+    }
+
+    // continues a search from cached state s by simulating the NFA
+    fn search_nfa<I: Iterator<Item=(usize, char)>>(&mut self, s: StateID, input: I,
+                                                   mut last: Option<(RuleID, usize)>) -> Option<(RuleID, usize)> {
+        self.cur.clear();
+        for &t in self.sets[s].iter() {
+            self.cur.insert(t);
+        }
+
+        for (end, c) in input {
+            self.nfa.step_set(&self.cur, c, &mut self.next);
+            ::std::mem::swap(&mut self.cur, &mut self.next);
+            if self.cur.is_empty() {
+                break;
+            }
+            if let Some(r) = self.nfa.state_set_rule(&self.cur) {
+                last = Some((r, end));
+            }
+        }
+
+        last
+    }
+
+    // returns the state reached from s by c, or DEAD; building a new
+    // state can flush the cache, so s may no longer be valid afterwards
+    fn next_state(&mut self, s: StateID, c: char) -> StateID {
+        self.since_flush += 1;
+        let k = self.classes.class_of(c);
+        let i = s * self.classes.len() + k;
+        if self.table[i] != UNKNOWN {
+            return self.table[i];
+        }
+
+        self.cur.clear();
+        for &t in self.sets[s].iter() {
+            self.cur.insert(t);
+        }
+        self.next.clear();
+        if let Some(rep) = self.reps[k] {
+            self.nfa.step_set(&self.cur, rep, &mut self.next);
+        }
+        if self.next.is_empty() {
+            self.table[i] = DEAD;
+            return DEAD;
+        }
+
+        let key = sorted_states(&self.next);
+        if let Some(&t) = self.state_map.get(&key) {
+            self.table[i] = t;
+            return t;
+        }
+        if self.sets.len() == self.capacity {
+            // s is gone with the rest of the cache
+            self.flush();
+            return self.add_state(key);
+        }
+        let t = self.add_state(key);
+        self.table[i] = t;
+        t
+    }
+
+    fn add_state(&mut self, set: Vec<StateID>) -> StateID {
+        let state = self.sets.len();
+        let nfa = self.nfa;
+        self.accept.push(set.iter().filter_map(|&i| nfa.get_state(i).unwrap().rule()).min());
+        self.table.extend((0..self.classes.len()).map(|_| UNKNOWN));
+        self.sets.push(set.clone());
+        self.state_map.insert(set, state);
+        state
+    }
+
+    // empties the cache, keeping only the start state
+    fn flush(&mut self) {
+        if self.flushes > 0 && self.since_flush < self.capacity {
+            self.thrashing = true;
+        }
+        self.flushes += 1;
+        self.since_flush = 0;
+
+        self.sets.truncate(1);
+        self.accept.truncate(1);
+        self.table.truncate(self.classes.len());
+        for t in self.table.iter_mut() {
+            *t = UNKNOWN;
+        }
+        let start = self.sets[0].clone();
+        self.state_map.clear();
+        self.state_map.insert(start, 0);
+    }
+}
+
+#[test]
+fn test_lazy_dfa() {
+    use dfa::DFA;
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let mut specs = vec![rules::regex_to_nfa_spec(&Expr::parse(r"if").unwrap()),
+                         rules::regex_to_nfa_spec(&Expr::parse(r"[a-z][a-z0-9]*").unwrap()),
+                         rules::regex_to_nfa_spec(&Expr::parse(r"[0-9]+").unwrap())];
+    let nfa = NFABuilder::build_from_specs(&mut specs);
+    let dfa = DFA::new(&nfa.to_dfa());
+    let mut lazy = LazyDFA::new(&nfa);
+
+    assert_eq!(lazy.num_cached_states(), 1);
+    for w in ["if", "iff", "i", "x9", "42", "4x", "", "!", "if(", "ifx", "123a"].iter() {
+        assert_eq!(lazy.longest_match(w), dfa.longest_match(w), "{}", w);
+        assert_eq!(lazy.simulate(w), dfa.simulate(w), "{}", w);
+    }
+    assert!(lazy.num_cached_states() <= dfa.num_states());
+    assert_eq!(lazy.cache_flushes(), 0);
+}
+
+#[test]
+fn test_lazy_dfa_flush() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    // needs 2^6 DFA states to remember the last 6 characters
+    let spec = rules::regex_to_nfa_spec(&Expr::parse(r"(a|b)*a(a|b){5}").unwrap());
+    let nfa = NFABuilder::build_from_spec(spec);
+    let words = ["abbbbb", "aabbbbb", "babababab", "bbbbbbbbbbbbabbbbb", "ababbaaababbbbaaabbbab", "a", ""];
+
+    for &capacity in [2, 8, 64, 1024].iter() {
+        let mut lazy = LazyDFA::with_capacity(&nfa, capacity);
+        for w in words.iter() {
+            assert_eq!(lazy.simulate(w), nfa.simulate(w), "{} with {} states", w, capacity);
+            assert_eq!(lazy.longest_match(w), nfa.longest_match(w), "{} with {} states", w, capacity);
+        }
+        assert!(lazy.num_cached_states() <= capacity);
+    }
+
+    let mut small = LazyDFA::with_capacity(&nfa, 8);
+    small.simulate("ababbaaababbbbaaabbbab");
+    assert!(small.cache_flushes() > 0);
+}
@@ -2,6 +2,22 @@ extern crate regex_syntax;
 extern crate regex;
 
 pub mod nfa;
+pub mod arena;
+pub mod classes;
+pub mod dfa;
+pub mod derivative;
+pub mod equivalence;
+pub mod lazy;
 pub mod rules;
 pub mod parser;
+pub mod pattern;
+pub mod pikevm;
+pub mod sample;
+pub mod codegen;
+pub mod diagnostics;
+pub mod stats;
+pub mod stateset;
+pub mod shiftand;
+pub mod tdfa;
+pub mod utf8;
 
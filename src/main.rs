@@ -0,0 +1,126 @@
+extern crate rslex;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::process;
+use std::time::Instant;
+
+use rslex::parser;
+use rslex::codegen;
+use rslex::diagnostics;
+use rslex::nfa;
+use rslex::stats;
+
+fn usage() -> ! {
+    eprintln!("usage: rslex [-v] [--overlaps] [--bytes] [--glushkov] [--max-states <n>] [--max-memory <MB>] <spec file> [-o <output file>]");
+    process::exit(2);
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("rslex: {}", msg);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut input = None;
+    let mut output = None;
+    let mut report_overlaps = false;
+    let mut verbose = false;
+    let mut options = codegen::Options::default();
+
+    let mut i = 0;
+    while i < args.len() {
+        match &args[i][..] {
+            "-o" if i + 1 < args.len() => {
+                output = Some(args[i + 1].clone());
+                i += 1;
+            },
+            "--overlaps" => report_overlaps = true,
+            "-v" => verbose = true,
+            "--bytes" => options.utf8_bytes = true,
+            "--glushkov" => options.construction = nfa::Construction::Glushkov,
+            "--max-states" if i + 1 < args.len() => {
+                options.limit.max_states = args[i + 1].parse().unwrap_or_else(|_| usage());
+                i += 1;
+            },
+            "--max-memory" if i + 1 < args.len() => {
+                let mb: usize = args[i + 1].parse().unwrap_or_else(|_| usage());
+                options.limit.max_bytes = mb << 20;
+                i += 1;
+            },
+            arg if input.is_none() && !arg.starts_with('-') => input = Some(arg.to_string()),
+            _ => usage()
+        }
+        i += 1;
+    }
+
+    let input = input.unwrap_or_else(|| usage());
+    let mut text = String::new();
+    if let Err(e) = File::open(&input).and_then(|mut f| f.read_to_string(&mut text)) {
+        fail(format!("{}: {}", input, e));
+    }
+
+    let start = Instant::now();
+    let spec = match parser::parse_spec(&text) {
+        Ok(spec) => spec,
+        Err(e) => fail(format!("{}: {}", input, e))
+    };
+    let parse_time = start.elapsed();
+    if report_overlaps {
+        match diagnostics::overlap_report(&spec, &options.limit) {
+            Ok(report) => print!("{}", report),
+            Err(d) => {
+                eprintln!("rslex: {}: {}", input, d);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // the checks explore the same automata as the generation, so they
+    // only run once these are known to be within the limits
+    let code = match codegen::generate_with(&spec, &options) {
+        Ok(code) => code,
+        Err(d) => {
+            eprintln!("rslex: {}: {}", input, d);
+            process::exit(1);
+        }
+    };
+    let diags = match diagnostics::check(&spec, &options.limit) {
+        Ok(diags) => diags,
+        Err(d) => {
+            eprintln!("rslex: {}: {}", input, d);
+            process::exit(1);
+        }
+    };
+    for d in diags.iter() {
+        eprintln!("rslex: {}: {}", input, d);
+    }
+    if diagnostics::has_errors(&diags) {
+        process::exit(1);
+    }
+    if verbose {
+        let ms = parse_time.as_secs() as f64 * 1000.0 + parse_time.subsec_nanos() as f64 / 1.0e6;
+        eprintln!("rslex: {}: {} rules, parsed in {:.3} ms", input, spec.rules.len(), ms);
+        match stats::spec_stats(&spec, &options) {
+            Ok(stats) => for s in stats {
+                eprint!("{}", s);
+            },
+            Err(d) => {
+                eprintln!("rslex: {}: {}", input, d);
+                process::exit(1);
+            }
+        }
+    }
+
+    let res = match output {
+        Some(ref path) => File::create(path).and_then(|mut f| f.write_all(code.as_bytes())),
+        None => std::io::stdout().write_all(code.as_bytes())
+    };
+    if let Err(e) = res {
+        fail(format!("{}", e));
+    }
+}
@@ -7,74 +7,182 @@ use std::fmt;
 use std::fs::File;
 use std::io::Write;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::VecDeque;
 use std::collections::BTreeSet;
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::mem;
+
+use arena::SpecArena;
+use arena::SpecID;
+use arena::SpecNode;
+use classes::CharClasses;
+use rules;
+use rules::Repetition;
+use stateset::StateSet;
 
 pub type StateID = usize;
+pub type RuleID = usize;
 
 pub struct State {
     //id: StateID,
     accept: bool,
-    trans: Vec<Transition>
+    rule: Option<RuleID>,
+    trans: Vec<Transition>,
+    // capture slot where the position is saved when passing through
+    save: Option<usize>
 }
 
 impl State {
     // finds the outgoing transitions for a given label
+    #[cfg(test)]
     fn find_transition(&self, label: Label) -> Vec<&Transition> {
         let mut res : Vec<&Transition> = Vec::new();
         for t in self.trans.iter() {
             if t.label == label {
-                res.push(&t);
+                res.push(t);
             }
         }
 
         res
     }
 
-    fn step(&self, c: char) -> BTreeSet<StateID> {
-        let mut res : BTreeSet<StateID> = BTreeSet::new();
+    /// Calls `f` on the targets of the transitions taken by `c`; `Any`
+    /// transitions are only taken for characters that have no `Symbol`
+    /// or `Class` transition from this state.
+    pub fn step_each<F: FnMut(StateID)>(&self, c: char, mut f: F) {
+        let mut matched = false;
         for t in self.trans.iter() {
-            match t.label {
-                Label::Any => res.insert(t.target),
-                Label::Symbol(s) if s == c => res.insert(t.target),
-                _ => true
-            };
+            if t.label.matches(c) {
+                matched = true;
+                f(t.target);
+            }
+        }
+
+        if !matched {
+            for t in self.trans.iter() {
+                if t.label == Label::Any {
+                    f(t.target);
+                }
+            }
         }
+    }
+
+    // the ranges of characters matched by the Symbol and Class transitions
+    fn explicit_ranges(&self) -> Vec<(char, char)> {
+        let mut res: Vec<(char, char)> = self.trans.iter().flat_map(|t| t.label.ranges()).collect();
+        res.sort();
+        res
+    }
 
+    fn step(&self, c: char) -> BTreeSet<StateID> {
+        let mut res : BTreeSet<StateID> = BTreeSet::new();
+        self.step_each(c, |t| { res.insert(t); });
         res
     }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accept
+    }
+
+    // the rule recognized by this state, if accepting
+    pub fn rule(&self) -> Option<RuleID> {
+        self.rule
+    }
+
+    /// Returns the capture slot where the position is saved when passing
+    /// through this state, if any.
+    pub fn save(&self) -> Option<usize> {
+        self.save
+    }
+
+    pub fn transitions(&self) -> &[Transition] {
+        &self.trans
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Hash, PartialOrd, Ord, Clone)]
 pub enum Label {
     Epsilon,
+    // matches every character without a Symbol or Class transition from the same state
     Any,
-    Symbol(char)
+    Symbol(char),
+    // sorted, disjoint and non-adjacent ranges of characters
+    Class(Vec<(char, char)>)
 }
 
 impl Label {
-    fn to_string(&self) -> String {
+    fn text(&self) -> String {
         let mut res = String::new();
         match *self {
             Label::Any => res.push('*'),
             Label::Epsilon => res.push_str("&epsilon;"),
-            Label::Symbol(c) => res.push(c)
+            Label::Symbol(c) => res.push(c),
+            Label::Class(ref ranges) => {
+                res.push('[');
+                for &(lo, hi) in ranges.iter() {
+                    res.push(lo);
+                    if hi != lo {
+                        res.push('-');
+                        res.push(hi);
+                    }
+                }
+                res.push(']');
+            }
         }
         res
     }
+
+    // whether a transition with this label is taken by c; Any is not
+    // considered, as it depends on the other transitions
+    pub fn matches(&self, c: char) -> bool {
+        match *self {
+            Label::Symbol(s) => s == c,
+            Label::Class(ref ranges) => ranges.binary_search_by(|&(lo, hi)| {
+                if hi < c {
+                    Ordering::Less
+                } else if lo > c {
+                    Ordering::Greater
+                } else {
+                    Ordering::Equal
+                }
+            }).is_ok(),
+            _ => false
+        }
+    }
+
+    // ranges of the characters matched by the label
+    pub fn ranges(&self) -> Vec<(char, char)> {
+        match *self {
+            Label::Symbol(c) => vec![(c, c)],
+            Label::Class(ref ranges) => ranges.clone(),
+            _ => Vec::new()
+        }
+    }
 }
 
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_string())
+        write!(f, "{}", self.text())
     }
 }
 
-struct Transition {
+pub struct Transition {
     label: Label,
     target: StateID
 }
 
+impl Transition {
+    pub fn label(&self) -> &Label {
+        &self.label
+    }
+
+    pub fn target(&self) -> StateID {
+        self.target
+    }
+}
+
 
 #[test]
 fn states() {
@@ -128,175 +236,794 @@ fn transitions() {
     assert_eq!(t1[1].target, id3);
 }
 
-fn canonical_set_string(bset: &BTreeSet<StateID>) -> String {
-    let mut res = String::new();
+// estimated memory for a DFA state with the NFA states in set, which
+// are stored in the list of sets and as a key of the map
+fn state_bytes(set: &[StateID]) -> usize {
+    mem::size_of::<State>() + 2 * set.len() * mem::size_of::<StateID>()
+}
 
-    for id in bset.iter() {
-        res.push_str(&format!("{} ", id));
+// the characters not in the sorted ranges, which can overlap
+fn complement(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut res = Vec::new();
+    // next code point not covered by the ranges seen so far
+    let mut next = 0u32;
+    for &(lo, hi) in ranges.iter() {
+        if lo as u32 > next {
+            push_code_points(next, lo as u32 - 1, &mut res);
+        }
+        next = ::std::cmp::max(next, hi as u32 + 1);
     }
+    push_code_points(next, ::std::char::MAX as u32, &mut res);
+    res
+}
+
+// adds the characters in the code points lo..hi, skipping surrogates
+fn push_code_points(lo: u32, hi: u32, res: &mut Vec<(char, char)>) {
+    for &(l, h) in [(lo, ::std::cmp::min(hi, 0xD7FF)), (::std::cmp::max(lo, 0xE000), hi)].iter() {
+        if l <= h {
+            res.push((::std::char::from_u32(l).unwrap(), ::std::char::from_u32(h).unwrap()));
+        }
+    }
+}
 
+/// Returns the states in `set` in order, to identify the set.
+pub fn sorted_states(set: &StateSet) -> Vec<StateID> {
+    let mut res: Vec<StateID> = set.iter().cloned().collect();
+    res.sort();
     res
 }
 
+/// Limits on the size of the DFA built by `NFA::to_dfa_limited`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DfaLimit {
+    /// Maximum number of states.
+    pub max_states: usize,
+    /// Maximum memory used by the construction, in bytes (an estimate).
+    pub max_bytes: usize
+}
+
+impl DfaLimit {
+    /// No limits: the construction runs until it is done.
+    pub fn unlimited() -> DfaLimit {
+        DfaLimit { max_states: usize::MAX, max_bytes: usize::MAX }
+    }
+}
+
+impl Default for DfaLimit {
+    fn default() -> DfaLimit {
+        DfaLimit { max_states: 10000, max_bytes: 64 << 20 }
+    }
+}
+
+/// Failure of `NFA::to_dfa_limited` for exceeding its limits.
+#[derive(Debug, PartialEq)]
+pub struct StateExplosion {
+    /// States built when the construction was aborted.
+    pub states: usize,
+    /// Estimated memory used when the construction was aborted.
+    pub bytes: usize,
+    /// The rule that contributes the most states, if the automaton has
+    /// rule tags.
+    pub rule: Option<RuleID>
+}
+
+/// Boolean operation on the languages of two DFAs, for `NFA::product`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOp {
+    Intersection,
+    Union,
+    /// The strings of the first language that are not in the second.
+    Difference
+}
+
+impl SetOp {
+    // whether a string is in the result, given whether it is in each of
+    // the languages; also whether a state of the product can still reach
+    // an accepting state, given whether the states of the operands exist
+    fn apply(self, a: bool, b: bool) -> bool {
+        match self {
+            SetOp::Intersection => a && b,
+            SetOp::Union => a || b,
+            SetOp::Difference => a && !b
+        }
+    }
+
+    fn live(self, a: bool, b: bool) -> bool {
+        match self {
+            SetOp::Intersection => a && b,
+            SetOp::Union => a || b,
+            SetOp::Difference => a
+        }
+    }
+}
 
 pub struct NFA {
     start: StateID,
     //accept: StateID,
     states: Vec<State>,
     //tables: Vec<HashMap<Label, Vec<StateID>>>
+    // epsilon closure of each state
+    closures: Vec<Vec<StateID>>
 }
 
 impl NFA {
+    fn new(states: Vec<State>, start: StateID) -> NFA {
+        let mut closures = Vec::with_capacity(states.len());
+        let mut seen = StateSet::new(states.len());
+        let mut stack : Vec<StateID> = Vec::new();
+
+        for s in 0..states.len() {
+            seen.clear();
+            seen.insert(s);
+            stack.push(s);
+            while let Some(s) = stack.pop() {
+                for t in states[s].trans.iter() {
+                    if t.label == Label::Epsilon && seen.insert(t.target) {
+                        stack.push(t.target);
+                    }
+                }
+            }
+            closures.push(seen.iter().cloned().collect());
+        }
+
+        NFA { start, states, closures }
+    }
+
     pub fn get_state(&self, sid: StateID) -> Option<&State> {
         self.states.get(sid)
     }
 
+    pub fn start_state(&self) -> StateID {
+        self.start
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.states.len()
+    }
+
     fn epsilon_closure(&self, states: &BTreeSet<StateID>) -> BTreeSet<StateID> {
         let mut clos : BTreeSet<StateID> = BTreeSet::new();
-        let mut stack : Vec<StateID> = Vec::with_capacity(states.len());
-
-        stack.extend(states.clone());
-        clos.extend(states.clone());
-
-        while !stack.is_empty() {
-            let s = stack.pop().unwrap();         // safe to unwrap because stack is not empty
-            let st = self.get_state(s).unwrap();  // safe to unwrap because states on the stack must exist
-            for t in st.find_transition(Label::Epsilon).iter() {
-                if !clos.contains(&t.target) {
-                    clos.insert(t.target);
-                    stack.push(t.target);
-                }
-            }
+        for &s in states.iter() {
+            clos.extend(self.closures[s].iter().cloned());
         }
 
         clos
     }
 
+    // adds the epsilon closure of s to set
+    fn add_closure(&self, set: &mut StateSet, s: StateID) {
+        for &t in self.closures[s].iter() {
+            set.insert(t);
+        }
+    }
+
+    /// Makes `next` the epsilon closure of the states reached from the
+    /// states in `cur` by `c`.
+    pub fn step_set(&self, cur: &StateSet, c: char, next: &mut StateSet) {
+        next.clear();
+        for &s in cur.iter() {
+            self.states[s].step_each(c, |t| self.add_closure(next, t));
+        }
+    }
+
+    /// Returns the epsilon closure of the start state.
+    pub fn start_set(&self) -> StateSet {
+        let mut set = StateSet::new(self.states.len());
+        self.add_closure(&mut set, self.start);
+        set
+    }
+
+    /// Returns the highest priority (lowest id) rule recognized by any
+    /// state in `set`.
+    pub fn state_set_rule(&self, set: &StateSet) -> Option<RuleID> {
+        set.iter().filter_map(|&i| self.states[i].rule).min()
+    }
+
     fn step(&self, s: StateID, c: char) -> BTreeSet<StateID> {
         let st = self.get_state(s).unwrap();
         st.step(c)
     }
 
+    // the state reached from s by c in a DFA built by to_dfa
+    pub fn step_deterministic(&self, s: StateID, c: char) -> Option<StateID> {
+        self.step(s, c).iter().next().cloned()
+    }
+
     fn steps(&self, vs: &BTreeSet<StateID>, c: char) -> BTreeSet<StateID> {
         let mut res : BTreeSet<StateID> = BTreeSet::new();
         for s in vs.iter() {
-            let ss = self.step(*s, c);
-            res = res.union(&ss).cloned().collect();
+            self.states[*s].step_each(c, |t| { res.insert(t); });
         }
 
         res
     }
 
-    // this is terribly inefficient for the moment, will
-    // optimize if/when necessary
     pub fn simulate(&self, word: &str) -> bool {
-        let mut s = self.epsilon_closure(&state_set(self.start));
+        let mut s = self.start_set();
+        let mut next = StateSet::new(self.states.len());
         for c in word.chars() {
-            s = self.epsilon_closure(&self.steps(&s, c));
+            self.step_set(&s, c, &mut next);
+            ::std::mem::swap(&mut s, &mut next);
+            if s.is_empty() {
+                return false;
+            }
         }
-        s.iter().any(|&i| self.get_state(i).unwrap().accept)
+        s.iter().any(|&i| self.states[i].accept)
         //s.contains(&self.accept)
     }
 
-    fn transitions_from_set(&self, set: &BTreeSet<StateID>) -> BTreeSet<&Label> {
-        let mut labels = BTreeSet::new();
-        for sid in set.iter() {
-            let state = self.get_state(*sid).unwrap();
-            for trans in state.trans.iter() {
-                labels.insert(&trans.label);
+    /// Returns whether the automaton accepts the empty string, i.e. if an
+    /// accepting state is in the epsilon closure of the start state.
+    pub fn accepts_empty(&self) -> bool {
+        self.closures[self.start].iter().any(|&i| self.states[i].accept)
+    }
+
+    /// Finds the longest prefix of `input` recognized by the automaton.
+    ///
+    /// The return value is the rule recognized for that prefix together
+    /// with its length in bytes, or `None` if no prefix (not even the
+    /// empty one) is accepted. When a prefix is recognized by more than
+    /// one rule, the one with the lowest id wins.
+    pub fn longest_match(&self, input: &str) -> Option<(RuleID, usize)> {
+        let mut s = self.start_set();
+        let mut next = StateSet::new(self.states.len());
+        let mut last = self.state_set_rule(&s).map(|r| (r, 0));
+
+        for (i, c) in input.char_indices() {
+            self.step_set(&s, c, &mut next);
+            ::std::mem::swap(&mut s, &mut next);
+            if s.is_empty() {
+                break;
+            }
+            if let Some(r) = self.state_set_rule(&s) {
+                last = Some((r, i + c.len_utf8()));
             }
         }
-        labels
+
+        last
     }
 
-    // fn set_state(set: &BTreeSet<StateID>, builder: &mut NFABuilder, map: &mut HashMap<String, StateID>) -> StateID {
-    //     match map.contains(canonical_set_string(set)) {
-    //         None => {
-    //             let state = builder.new_state();
-    //             map.insert(set, state);
-    //             state
-    //         },
-    //         Some(state) => state
-    //     }
-    // }
+    /// Computes the classes of input characters that the transitions of
+    /// the automaton cannot tell apart.
+    pub fn char_classes(&self) -> CharClasses {
+        CharClasses::new(self.states.iter().flat_map(|st| st.trans.iter()).map(|t| &t.label))
+    }
 
     pub fn to_dfa(&self) -> Self {
+        // cannot fail without limits
+        self.to_dfa_limited(&DfaLimit::unlimited()).unwrap()
+    }
+
+    /// Builds a DFA like `to_dfa`, but gives up as soon as it exceeds
+    /// `limit`, since the number of states can grow exponentially with
+    /// the size of the automaton.
+    pub fn to_dfa_limited(&self, limit: &DfaLimit) -> Result<NFA, StateExplosion> {
+        let mut builder = NFABuilder::new();
+        // the sets of NFA states, as sorted vectors, for each DFA state
+        let mut sets: Vec<Vec<StateID>> = Vec::new();
+        let mut state_map: HashMap<Vec<StateID>, StateID> = HashMap::new();
+        let classes = self.char_classes();
+        let reps: Vec<Option<char>> = (0..classes.len()).map(|k| classes.representative(k)).collect();
+        let labels: Vec<Label> = (0..classes.len()).map(|k| classes.label(k)).collect();
+
+        let mut cur = self.start_set();
+        let mut next = StateSet::new(self.states.len());
+        let start = self.dfa_state(&cur, &mut builder, &mut sets, &mut state_map);
+        let mut bytes = state_bytes(&sets[start]);
+
+        // DFA states are numbered in the order they are found, so the
+        // states still to be processed are the ones after state
+        let mut state = 0;
+        while state < sets.len() {
+            cur.clear();
+            for &s in sets[state].iter() {
+                cur.insert(s);
+            }
+            for k in 0..classes.len() {
+                // for each input class, step from the set using a character of the class
+                let c = match reps[k] {
+                    Some(c) => c,
+                    None => continue
+                };
+                self.step_set(&cur, c, &mut next);
+                if next.is_empty() {
+                    continue;
+                }
+                let new_state = self.dfa_state(&next, &mut builder, &mut sets, &mut state_map);
+                builder.add_transition(state, new_state, labels[k].clone());
+                if new_state + 1 == sets.len() {
+                    bytes += state_bytes(&sets[new_state]);
+                }
+                bytes += mem::size_of::<Transition>();
+                if sets.len() > limit.max_states || bytes > limit.max_bytes {
+                    return Err(StateExplosion { states: sets.len(), bytes,
+                                                rule: self.largest_rule(&sets) });
+                }
+            }
+            state += 1;
+        }
+
+        Ok(NFA::new(builder.states, start))
+    }
+
+    // the rule with the most distinct sets of its own states among sets,
+    // which is the one that makes the DFA grow the most; each state
+    // belongs to the first rule it can lead to
+    fn largest_rule(&self, sets: &[Vec<StateID>]) -> Option<RuleID> {
+        let mut preds: Vec<Vec<StateID>> = vec![Vec::new(); self.states.len()];
+        for (s, st) in self.states.iter().enumerate() {
+            for t in st.trans.iter() {
+                preds[t.target].push(s);
+            }
+        }
+
+        let mut tagged: Vec<(RuleID, StateID)> = self.states.iter().enumerate()
+            .filter_map(|(s, st)| st.rule.map(|r| (r, s))).collect();
+        tagged.sort();
+        let mut owner: Vec<Option<RuleID>> = vec![None; self.states.len()];
+        for &(r, s) in tagged.iter() {
+            let mut stack = vec![s];
+            while let Some(s) = stack.pop() {
+                if owner[s].is_none() {
+                    owner[s] = Some(r);
+                    stack.extend(preds[s].iter().cloned());
+                }
+            }
+        }
+
+        let mut projections: HashMap<RuleID, HashSet<Vec<StateID>>> = HashMap::new();
+        for set in sets.iter() {
+            let mut parts: HashMap<RuleID, Vec<StateID>> = HashMap::new();
+            for &s in set.iter() {
+                if let Some(r) = owner[s] {
+                    parts.entry(r).or_default().push(s);
+                }
+            }
+            for (r, part) in parts {
+                projections.entry(r).or_default().insert(part);
+            }
+        }
+
+        // the lowest rule among the ones with the most sets
+        projections.iter().map(|(&r, ps)| (ps.len(), Reverse(r))).max().map(|(_, Reverse(r))| r)
+    }
+
+    // finds the DFA state for a set of NFA states, creating it if the set
+    // is new
+    fn dfa_state(&self, set: &StateSet, builder: &mut NFABuilder, sets: &mut Vec<Vec<StateID>>,
+                 state_map: &mut HashMap<Vec<StateID>, StateID>) -> StateID {
+        let key = sorted_states(set);
+        if let Some(&state) = state_map.get(&key) {
+            return state;
+        }
+
+        let state = builder.new_state();
+        if set.iter().any(|&i| self.states[i].accept) {
+            builder.set_accepting(state);
+        }
+        if let Some(rule) = self.state_set_rule(set) {
+            builder.set_rule(state, rule);
+        }
+        sets.push(key.clone());
+        state_map.insert(key, state);
+        state
+    }
+
+    /// Builds an equivalent NFA without epsilon transitions.
+    ///
+    /// Each state takes the transitions, the accept flag and the rule of
+    /// the states in its epsilon closure, and only the start state and
+    /// the targets of other transitions are kept, if they are reachable.
+    /// Capture slots are not kept.
+    /// An `Any` transition moved into a state with other transitions is
+    /// replaced by a class of the characters it took in its own state.
+    pub fn remove_epsilons(&self) -> NFA {
+        // new ids of the states kept, given in the order they are reached
+        let mut ids: Vec<Option<StateID>> = vec![None; self.states.len()];
+        let mut order = vec![self.start];
+        ids[self.start] = Some(0);
+        let mut states: Vec<State> = Vec::new();
+
+        let mut i = 0;
+        while i < order.len() {
+            let clos = &self.closures[order[i]];
+            // other transitions would now block the Any ones
+            let explicit = clos.iter().any(|&q| !self.states[q].explicit_ranges().is_empty());
+            let mut trans: Vec<(Label, StateID)> = Vec::new();
+            for &q in clos.iter() {
+                let st = &self.states[q];
+                for t in st.trans.iter() {
+                    match t.label {
+                        Label::Epsilon => (),
+                        Label::Any if explicit => {
+                            // a state with transitions for all characters leaves none for Any
+                            let others = complement(&st.explicit_ranges());
+                            if !others.is_empty() {
+                                trans.push((Label::Class(others), t.target));
+                            }
+                        },
+                        _ => trans.push((t.label.clone(), t.target))
+                    }
+                }
+            }
+            trans.sort();
+            trans.dedup();
+
+            let mut st = State {
+                trans: Vec::with_capacity(trans.len()),
+                accept: clos.iter().any(|&q| self.states[q].accept),
+                rule: clos.iter().filter_map(|&q| self.states[q].rule).min(),
+                save: None
+            };
+            for (label, target) in trans {
+                if ids[target].is_none() {
+                    ids[target] = Some(order.len());
+                    order.push(target);
+                }
+                st.trans.push(Transition { label, target: ids[target].unwrap() });
+            }
+            states.push(st);
+            i += 1;
+        }
+
+        NFA::new(states, 0)
+    }
+
+    pub fn num_transitions(&self) -> usize {
+        self.states.iter().map(|st| st.trans.len()).sum()
+    }
+
+    pub fn num_input_classes(&self) -> usize {
+        self.char_classes().len()
+    }
+
+    /// Minimizes a DFA built by `to_dfa`.
+    ///
+    /// States are merged by partition refinement (Moore's algorithm),
+    /// starting from a partition by the rule recognized in each state,
+    /// so the rule tags are preserved.
+    pub fn minimize(&self) -> Self {
+        let classes = self.char_classes();
+        let alphabet: Vec<char> = (0..classes.len()).filter_map(|k| classes.representative(k)).collect();
+        let mut block: Vec<usize> = Vec::with_capacity(self.states.len());
+        let mut nblocks = {
+            let mut initial = HashMap::new();
+            for st in self.states.iter() {
+                let next = initial.len();
+                block.push(*initial.entry((st.accept, st.rule)).or_insert(next));
+            }
+            initial.len()
+        };
+
+        loop {
+            // states stay together if they go to the same blocks on every character
+            let mut signatures = HashMap::new();
+            let mut new_block = Vec::with_capacity(self.states.len());
+            for (sid, st) in self.states.iter().enumerate() {
+                let targets: Vec<Option<usize>> = alphabet.iter()
+                    .map(|&c| st.step(c).iter().next().map(|&t| block[t])).collect();
+                let next = signatures.len();
+                new_block.push(*signatures.entry((block[sid], targets)).or_insert(next));
+            }
+
+            block = new_block;
+            if signatures.len() == nblocks {
+                break;
+            }
+            nblocks = signatures.len();
+        }
+
         let mut builder = NFABuilder::new();
+        let mut done = vec![false; nblocks];
+        for _ in 0..nblocks {
+            builder.new_state();
+        }
+        for (sid, st) in self.states.iter().enumerate() {
+            let b = block[sid];
+            if done[b] {
+                continue;
+            }
+            done[b] = true;
+            if st.accept {
+                builder.set_accepting(b);
+            }
+            if let Some(rule) = st.rule {
+                builder.set_rule(b, rule);
+            }
+            for t in st.trans.iter() {
+                builder.add_transition(b, block[t.target], t.label.clone());
+            }
+        }
+
+        NFA::new(builder.states, block[self.start])
+    }
+
+    /// Analyzes how the rules of a combined automaton compete.
+    ///
+    /// The return value has one entry for each rule in `0..nrules`. The
+    /// sets of states reachable by each input are explored in order of
+    /// input length, as in `to_dfa`, so example strings are shortest.
+    pub fn rule_usage(&self, nrules: usize) -> Vec<RuleUsage> {
+        let mut usage: Vec<RuleUsage> = (0..nrules).map(|_| RuleUsage { example: None, wins: false }).collect();
         let mut queue = VecDeque::new();
-        //let mut marked = Vec::new();
-        let mut state_map = HashMap::new();
+        let mut seen = HashSet::new();
+
+        let classes = self.char_classes();
+        let reps: Vec<char> = (0..classes.len()).filter_map(|k| classes.representative(k)).collect();
 
         let startset = self.epsilon_closure(&state_set(self.start));
-        let start = builder.new_state();
-        state_map.insert(canonical_set_string(&startset), start);
-        queue.push_back(startset);
-
-        while let Some(set) = queue.pop_front() {
-            //println!("set in queue: {}", canonical_set_string(&set));
-            let state = *(state_map.get(&canonical_set_string(&set)).unwrap());
-            for label in self.transitions_from_set(&set) {
-                // for each transition from set
-                // check if resulting set is already in map; if not, create new state and put in map
-                match *label {
-                    Label::Epsilon => (),    // do nothing for epsilon transitions, will be picked up in epsilon_closure
-                    Label::Symbol(c) => {
-                        let new_set = self.epsilon_closure(&self.steps(&set, c));
-                        let new_state_opt = state_map.get(&canonical_set_string(&new_set)).map(|state| *state);
-                        let new_state = match new_state_opt {
-                            None => {
-                                let state = builder.new_state();
-                                if new_set.iter().any(|&i| self.get_state(i).unwrap().accept) {
-                                    builder.set_accepting(state);
-                                }
-                                state_map.insert(canonical_set_string(&new_set), state);
-                                queue.push_back(new_set);
-                                state
-                            },
-                            Some(state) => state
-                        };
-
-                        builder.add_transition(state, new_state, Label::Symbol(c));
-                    },
-                    Label::Any => ()  // TODO
+        seen.insert(startset.iter().cloned().collect::<Vec<StateID>>());
+        queue.push_back((startset, String::new()));
+
+        while let Some((set, word)) = queue.pop_front() {
+            let rules: BTreeSet<RuleID> = set.iter().filter_map(|&i| self.get_state(i).unwrap().rule).collect();
+            if let Some(&winner) = rules.iter().next() {
+                usage[winner].wins = true;
+                for &r in rules.iter() {
+                    if usage[r].example.is_none() {
+                        usage[r].example = Some((word.clone(), winner));
+                    }
+                }
+            }
+
+            for &c in reps.iter() {
+                let new_set = self.epsilon_closure(&self.steps(&set, c));
+                if !new_set.is_empty() && seen.insert(new_set.iter().cloned().collect::<Vec<StateID>>()) {
+                    let mut new_word = word.clone();
+                    new_word.push(c);
+                    queue.push_back((new_set, new_word));
+                }
+            }
+        }
+
+        usage
+    }
+
+    // a shortest string leading from the start state to each state, found
+    // by a breadth-first search over the states, with one character of
+    // each class of input that the transitions of a state tell apart
+    fn shortest_words(&self) -> Vec<Option<String>> {
+        let mut words: Vec<Option<String>> = vec![None; self.states.len()];
+        let mut queue = VecDeque::new();
+        for &s in self.closures[self.start].iter() {
+            words[s] = Some(String::new());
+            queue.push_back(s);
+        }
+
+        let mut targets = Vec::new();
+        while let Some(s) = queue.pop_front() {
+            let word = words[s].clone().unwrap();
+            let st = &self.states[s];
+            let classes = CharClasses::new(st.trans.iter().map(|t| &t.label));
+            for k in 0..classes.len() {
+                let c = match classes.representative(k) {
+                    Some(c) => c,
+                    None => continue
+                };
+                targets.clear();
+                st.step_each(c, |t| targets.push(t));
+                for &t in targets.iter() {
+                    for &u in self.closures[t].iter() {
+                        if words[u].is_none() {
+                            let mut new_word = word.clone();
+                            new_word.push(c);
+                            words[u] = Some(new_word);
+                            queue.push_back(u);
+                        }
+                    }
+                }
+            }
+        }
+
+        words
+    }
+
+    // the first in shortlex order of the shortest words of the states
+    // satisfying p
+    fn shortest_where<P: Fn(&State) -> bool>(&self, p: P) -> Option<String> {
+        self.shortest_words().into_iter().enumerate()
+            .filter(|&(s, _)| p(&self.states[s]))
+            .filter_map(|(_, w)| w)
+            .min_by(|w1, w2| (w1.chars().count(), w1).cmp(&(w2.chars().count(), w2)))
+    }
+
+    /// Finds a shortest string accepted by the automaton, or `None` if
+    /// it accepts none.
+    ///
+    /// Unlike `rule_usage`, the search goes over single states rather
+    /// than sets of states, so it takes time linear in the size of the
+    /// automaton.
+    pub fn shortest_accepted(&self) -> Option<String> {
+        self.shortest_where(|st| st.accept)
+    }
+
+    /// Finds a shortest string accepted by a state tagged with `rule`.
+    ///
+    /// In a combined automaton, this is a string the rule matches, even
+    /// if an earlier rule also matches it; in a DFA built by `to_dfa`,
+    /// where states are tagged with the rule that wins, it is a string
+    /// the rule wins.
+    pub fn shortest_accepted_by(&self, rule: RuleID) -> Option<String> {
+        self.shortest_where(|st| st.rule == Some(rule))
+    }
+
+    /// Returns whether the automaton accepts no string at all.
+    pub fn is_empty(&self) -> bool {
+        self.shortest_accepted().is_none()
+    }
+
+    /// Finds a shortest string accepted by both `self` and `other`.
+    ///
+    /// Both automata must be deterministic, as built by `to_dfa`. The
+    /// search is a breadth-first traversal of their product automaton.
+    pub fn shortest_common(&self, other: &NFA) -> Option<String> {
+        let mut queue = VecDeque::new();
+        let mut seen = HashSet::new();
+        let classes = CharClasses::new(self.states.iter().chain(other.states.iter())
+                                       .flat_map(|st| st.trans.iter()).map(|t| &t.label));
+        let reps: Vec<char> = (0..classes.len()).filter_map(|k| classes.representative(k)).collect();
+
+        seen.insert((self.start, other.start));
+        queue.push_back((self.start, other.start, String::new()));
+
+        while let Some((p, q, word)) = queue.pop_front() {
+            let sp = self.get_state(p).unwrap();
+            let sq = other.get_state(q).unwrap();
+            if sp.accept && sq.accept {
+                return Some(word);
+            }
+
+            for &c in reps.iter() {
+                if let (Some(&p2), Some(&q2)) = (sp.step(c).iter().next(), sq.step(c).iter().next()) {
+                    if seen.insert((p2, q2)) {
+                        let mut new_word = word.clone();
+                        new_word.push(c);
+                        queue.push_back((p2, q2, new_word));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Builds the product of two DFAs, which accepts the strings in the
+    /// result of `op` on their languages.
+    ///
+    /// Both automata must be deterministic, as built by `to_dfa`. A state
+    /// of the product is a pair of states of the operands, where a missing
+    /// transition leads to no state; the pairs that cannot accept are left
+    /// out. Accepting states keep the rule of the first automaton, or of
+    /// the second if only it accepts.
+    pub fn product(&self, other: &NFA, op: SetOp) -> NFA {
+        // cannot fail without limits
+        self.product_limited(other, op, &DfaLimit::unlimited()).unwrap()
+    }
+
+    /// Builds the DFA of `product`, but gives up as soon as it exceeds
+    /// `limit`, as it can have as many states as the two DFAs together.
+    pub fn product_limited(&self, other: &NFA, op: SetOp, limit: &DfaLimit) -> Result<NFA, StateExplosion> {
+        let classes = CharClasses::new(self.states.iter().chain(other.states.iter())
+                                       .flat_map(|st| st.trans.iter()).map(|t| &t.label));
+        let rule = |p: Option<StateID>, q: Option<StateID>| {
+            let rp = p.and_then(|p| self.states[p].rule);
+            let rq = q.and_then(|q| other.states[q].rule);
+            if op.apply(rp.is_some(), rq.is_some()) { rp.or(rq) } else { None }
+        };
+
+        let mut builder = NFABuilder::new();
+        let start = (Some(self.start), Some(other.start));
+        let mut pairs = vec![start];
+        let mut state_map = HashMap::new();
+        state_map.insert(start, builder.add_state(rule(start.0, start.1)));
+        let pair_bytes = mem::size_of::<State>() + 2 * mem::size_of::<(Option<StateID>, Option<StateID>)>();
+        let mut bytes = pair_bytes;
+
+        // states are numbered in the order they are found, so the states
+        // still to be processed are the ones after state
+        let mut state = 0;
+        while state < pairs.len() {
+            let (p, q) = pairs[state];
+            for k in 0..classes.len() {
+                let c = match classes.representative(k) {
+                    Some(c) => c,
+                    None => continue
+                };
+                let p2 = p.and_then(|p| self.step_deterministic(p, c));
+                let q2 = q.and_then(|q| other.step_deterministic(q, c));
+                if !op.live(p2.is_some(), q2.is_some()) {
+                    continue;
+                }
+                let target = match state_map.get(&(p2, q2)) {
+                    Some(&t) => t,
+                    None => {
+                        let t = builder.add_state(rule(p2, q2));
+                        pairs.push((p2, q2));
+                        state_map.insert((p2, q2), t);
+                        bytes += pair_bytes;
+                        t
+                    }
+                };
+                builder.add_transition(state, target, classes.label(k));
+                bytes += mem::size_of::<Transition>();
+                if pairs.len() > limit.max_states || bytes > limit.max_bytes {
+                    return Err(StateExplosion { states: pairs.len(), bytes, rule: None });
                 }
             }
+            state += 1;
         }
 
-        NFA { states: builder.states, start: start }
+        Ok(builder.finish(0))
+    }
+
+    /// Returns a DFA for the strings accepted by both `self` and `other`,
+    /// which must be DFAs.
+    pub fn intersection(&self, other: &NFA) -> NFA {
+        self.product(other, SetOp::Intersection)
+    }
+
+    /// Returns a DFA for the strings accepted by `self` or `other`, which
+    /// must be DFAs.
+    pub fn union(&self, other: &NFA) -> NFA {
+        self.product(other, SetOp::Union)
+    }
+
+    /// Returns a DFA for the strings accepted by `self` but not by
+    /// `other`, which must be DFAs.
+    pub fn difference(&self, other: &NFA) -> NFA {
+        self.product(other, SetOp::Difference)
+    }
+
+    /// Returns a DFA for the strings, over all of Unicode, that `self`,
+    /// a DFA, rejects; its accepting states are tagged with rule 0.
+    pub fn complement(&self) -> NFA {
+        let mut builder = NFABuilder::new();
+        let all = builder.add_state(Some(0));
+        builder.add_transition(all, all, Label::Any);
+        builder.finish(all).difference(self)
     }
 
     pub fn dot_output(&self, filename: &str) {
         let mut buffer = File::create(filename).unwrap();
 
-        buffer.write(b"digraph {\n").unwrap();
-        buffer.write(b"  graph [rankdir=LR]\n").unwrap();
-        buffer.write(b"  node [shape=circle]\n").unwrap();
+        buffer.write_all(b"digraph {\n").unwrap();
+        buffer.write_all(b"  graph [rankdir=LR]\n").unwrap();
+        buffer.write_all(b"  node [shape=circle]\n").unwrap();
         // loop over state ids
         for sid in 0 .. self.states.len() {
             for trans in &self.states[sid].trans {
                 if self.get_state(trans.target).unwrap().accept {
-                    buffer.write(format!("  {} [shape=doublecircle]\n", trans.target).as_bytes()).unwrap();
+                    buffer.write_all(format!("  {} [shape=doublecircle]\n", trans.target).as_bytes()).unwrap();
                 }
-                buffer.write(format!("  {} -> {} [label = \"{}\"]\n", sid,
+                buffer.write_all(format!("  {} -> {} [label = \"{}\"]\n", sid,
                                      trans.target, trans.label).as_bytes()).unwrap();
             }
         }
-        buffer.write(b"  p [shape=point, style=invis]\n").unwrap();
-        buffer.write(format!("  p -> {}\n", self.start).as_bytes()).unwrap();
-        buffer.write(b"}\n").unwrap();
+        buffer.write_all(b"  p [shape=point, style=invis]\n").unwrap();
+        buffer.write_all(format!("  p -> {}\n", self.start).as_bytes()).unwrap();
+        buffer.write_all(b"}\n").unwrap();
     }
 }
 
 #[test]
-fn canonical_string() {
-    let mut set : BTreeSet<StateID> = BTreeSet::new();
+fn test_sorted_states() {
+    let mut set = StateSet::new(20);
 
     set.insert(15);
     set.insert(12);
     set.insert(8);
 
-    assert!(set.contains(&12));
-    assert_eq!(canonical_set_string(&set), "8 12 15 ");
+    assert!(set.contains(12));
+    assert_eq!(sorted_states(&set), vec![8, 12, 15]);
+}
+
+/// Outcome of a rule in a combined automaton, as found by `NFA::rule_usage`.
+pub struct RuleUsage {
+    /// A shortest string accepted by the rule, with the rule that wins it
+    /// (the rule itself, unless an earlier rule also accepts the string).
+    pub example: Option<(String, RuleID)>,
+    /// Whether the rule wins for some string.
+    pub wins: bool,
 }
 
 fn state_set(s: StateID) -> BTreeSet<StateID> {
@@ -311,7 +1038,15 @@ pub enum Spec {
     Single(Label),
     Union(Box<Spec>, Box<Spec>),
     Concat(Box<Spec>, Box<Spec>),
-    Star(Box<Spec>)
+    Star(Box<Spec>),
+    /// Capturing group `i` (from 1) around a spec, which only matters to
+    /// the simulations that record captures.
+    Group(usize, Box<Spec>),
+    /// The strings matched by both specs; built by determinizing them.
+    Intersection(Box<Spec>, Box<Spec>),
+    /// The strings, over all of Unicode, not matched by the spec; built
+    /// by determinizing it.
+    Complement(Box<Spec>)
 }
 
 impl Spec {
@@ -331,6 +1066,179 @@ impl Spec {
     pub fn star(s: Box<Spec>) -> Box<Spec> {
         Box::new(Spec::Star(s))
     }
+
+    pub fn intersection(s1: Box<Spec>, s2: Box<Spec>) -> Box<Spec> {
+        Box::new(Spec::Intersection(s1, s2))
+    }
+
+    /// Returns the number of symbol positions: the labels other than
+    /// `Epsilon`.
+    pub fn positions(&self) -> usize {
+        match *self {
+            Spec::Single(Label::Epsilon) => 0,
+            Spec::Single(_) => 1,
+            Spec::Union(ref s1, ref s2) | Spec::Concat(ref s1, ref s2) => s1.positions() + s2.positions(),
+            Spec::Intersection(ref s1, ref s2) => s1.positions() + s2.positions(),
+            Spec::Star(ref s) | Spec::Group(_, ref s) | Spec::Complement(ref s) => s.positions()
+        }
+    }
+
+    /// Returns whether the spec has an intersection or a complement,
+    /// which the position automaton cannot represent.
+    pub fn has_boolean_ops(&self) -> bool {
+        match *self {
+            Spec::Single(_) => false,
+            Spec::Intersection(_, _) | Spec::Complement(_) => true,
+            Spec::Union(ref s1, ref s2) | Spec::Concat(ref s1, ref s2) => s1.has_boolean_ops() || s2.has_boolean_ops(),
+            Spec::Star(ref s) | Spec::Group(_, ref s) => s.has_boolean_ops()
+        }
+    }
+}
+
+/// Construction of automata from specs.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Construction {
+    /// Thompson's construction, with epsilon transitions; the default.
+    #[default]
+    Thompson,
+    /// The position automaton, without epsilon transitions.
+    Glushkov
+}
+
+/// Properties of a spec in the Glushkov construction, in terms of the
+/// positions of its symbols.
+pub struct Linear {
+    pub nullable: bool,
+    /// Positions that can match the first character of a string.
+    pub first: BTreeSet<usize>,
+    /// Positions that can match the last character of a string.
+    pub last: BTreeSet<usize>
+}
+
+/// The symbol positions of some specs, numbered from 0 in the order of
+/// the specs, with their labels and the positions that can follow each
+/// one.
+pub struct Positions {
+    pub labels: Vec<Label>,
+    pub follow: Vec<BTreeSet<usize>>
+}
+
+impl Default for Positions {
+    fn default() -> Positions {
+        Positions::new()
+    }
+}
+
+impl Positions {
+    pub fn new() -> Positions {
+        Positions { labels: Vec::new(), follow: Vec::new() }
+    }
+
+    /// Adds the positions of `s`.
+    pub fn linearize(&mut self, s: Spec) -> Linear {
+        let mut arena = SpecArena::new();
+        let id = arena.from_spec(&s);
+        self.linearize_node(&arena, id)
+    }
+
+    /// Adds the positions of the node `id` of `arena`. A node used
+    /// several times, or repeated, has positions for each of its uses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the node has an intersection or a complement.
+    pub fn linearize_node(&mut self, arena: &SpecArena, id: SpecID) -> Linear {
+        match *arena.node(id) {
+            SpecNode::Single(Label::Epsilon) => Positions::epsilon(),
+            SpecNode::Single(ref l) => {
+                let p = self.labels.len();
+                self.labels.push(l.clone());
+                self.follow.push(BTreeSet::new());
+                Linear { nullable: false, first: state_set(p), last: state_set(p) }
+            },
+            SpecNode::Union(s1, s2) => {
+                let l1 = self.linearize_node(arena, s1);
+                let l2 = self.linearize_node(arena, s2);
+                Positions::union(l1, l2)
+            },
+            SpecNode::Concat(s1, s2) => {
+                let l1 = self.linearize_node(arena, s1);
+                let l2 = self.linearize_node(arena, s2);
+                self.concat(l1, l2)
+            },
+            SpecNode::Star(s) => {
+                let l = self.linearize_node(arena, s);
+                self.star(l)
+            },
+            SpecNode::Repeat(s, min, max) => rules::repeat_range(&mut LinearCopies { pos: self, arena, node: s }, min, max),
+            SpecNode::Group(_, s) => self.linearize_node(arena, s),
+            SpecNode::Intersection(_, _) | SpecNode::Complement(_) => panic!("intersection and complement have no positions")
+        }
+    }
+
+    fn epsilon() -> Linear {
+        Linear { nullable: true, first: BTreeSet::new(), last: BTreeSet::new() }
+    }
+
+    fn union(mut l1: Linear, l2: Linear) -> Linear {
+        l1.nullable = l1.nullable || l2.nullable;
+        l1.first.extend(l2.first);
+        l1.last.extend(l2.last);
+        l1
+    }
+
+    fn concat(&mut self, mut l1: Linear, mut l2: Linear) -> Linear {
+        for &p in l1.last.iter() {
+            self.follow[p].extend(l2.first.iter().cloned());
+        }
+        if l1.nullable {
+            l1.first.extend(l2.first.iter().cloned());
+        }
+        if l2.nullable {
+            l2.last.extend(l1.last);
+        }
+        Linear { nullable: l1.nullable && l2.nullable, first: l1.first, last: l2.last }
+    }
+
+    fn star(&mut self, mut l: Linear) -> Linear {
+        for &p in l.last.iter() {
+            self.follow[p].extend(l.first.iter().cloned());
+        }
+        l.nullable = true;
+        l
+    }
+}
+
+// copies of a node of an arena added as positions, for
+// rules::repeat_range
+struct LinearCopies<'a, 'b> {
+    pos: &'a mut Positions,
+    arena: &'b SpecArena,
+    node: SpecID
+}
+
+impl<'a, 'b> Repetition for LinearCopies<'a, 'b> {
+    type Item = Linear;
+
+    fn copy(&mut self) -> Linear {
+        self.pos.linearize_node(self.arena, self.node)
+    }
+
+    fn epsilon(&mut self) -> Linear {
+        Positions::epsilon()
+    }
+
+    fn concat(&mut self, a: Linear, b: Linear) -> Linear {
+        self.pos.concat(a, b)
+    }
+
+    fn union(&mut self, a: Linear, b: Linear) -> Linear {
+        Positions::union(a, b)
+    }
+
+    fn star(&mut self, a: Linear) -> Linear {
+        self.pos.star(a)
+    }
 }
 
 struct NFAid {
@@ -338,61 +1246,259 @@ struct NFAid {
     accept: StateID
 }
 
+// copies of a node of an arena built as automata, for
+// rules::repeat_range
+struct NodeCopies<'a, 'b> {
+    builder: &'a mut NFABuilder,
+    arena: &'b SpecArena,
+    node: SpecID,
+    limit: &'b DfaLimit
+}
+
+impl<'a, 'b> Repetition for NodeCopies<'a, 'b> {
+    type Item = Result<NFAid, StateExplosion>;
+
+    fn copy(&mut self) -> Result<NFAid, StateExplosion> {
+        self.builder.build_node(self.arena, self.node, self.limit)
+    }
+
+    fn epsilon(&mut self) -> Result<NFAid, StateExplosion> {
+        Ok(self.builder.single(Label::Epsilon))
+    }
+
+    fn concat(&mut self, a: Result<NFAid, StateExplosion>, b: Result<NFAid, StateExplosion>) -> Result<NFAid, StateExplosion> {
+        Ok(self.builder.concat(a?, b?))
+    }
+
+    fn union(&mut self, a: Result<NFAid, StateExplosion>, b: Result<NFAid, StateExplosion>) -> Result<NFAid, StateExplosion> {
+        Ok(self.builder.union(a?, b?))
+    }
+
+    fn star(&mut self, a: Result<NFAid, StateExplosion>) -> Result<NFAid, StateExplosion> {
+        Ok(self.builder.star(a?))
+    }
+}
+
 pub struct NFABuilder {
     states: Vec<State>
 }
 
+impl Default for NFABuilder {
+    fn default() -> NFABuilder {
+        NFABuilder::new()
+    }
+}
+
 impl NFABuilder {
-    fn new() -> NFABuilder {
+    pub fn new() -> NFABuilder {
         let st : Vec<State> = Vec::with_capacity(10);
         NFABuilder { states: st }
     }
 
+    /// Adds a state, which is accepting and tagged with `rule` if it is
+    /// given, for building an automaton by other means than specs.
+    pub fn add_state(&mut self, rule: Option<RuleID>) -> StateID {
+        let id = self.new_state();
+        if let Some(r) = rule {
+            self.set_rule(id, r);
+        }
+        id
+    }
+
+    /// Returns the automaton built, with start state `start`.
+    pub fn finish(self, start: StateID) -> NFA {
+        NFA::new(self.states, start)
+    }
+
     pub fn build_from_spec(s: Spec) -> NFA {
         let st : Vec<State> = Vec::with_capacity(10);
         let mut builder = NFABuilder { states: st };
 
         let nid = builder.build(s);
-        builder.set_accepting(nid.accept);
+        builder.set_rule(nid.accept, 0);
 
-        NFA { states: builder.states, start: nid.start }
+        NFA::new(builder.states, nid.start)
     }
 
     // build NFA integrating all the specs; consumes (drains) the vector ss
+    // the accept states of each spec are tagged with its index in ss
     pub fn build_from_specs(ss: &mut Vec<Spec>) -> NFA {
         let mut builder = NFABuilder::new();
         let ns = ss.drain(..).map(|s| builder.build(s)).collect();
         let start = builder.fuse_nfas(ns);
 
-        NFA { states: builder.states, start: start }
+        NFA::new(builder.states, start)
     }
 
-    fn build(&mut self, s: Spec) -> NFAid {
-        match s {
-            Spec::Single(l) => self.single(l),
-            Spec::Union(s1, s2) => {
-                let n1 = self.build(*s1);
-                let n2 = self.build(*s2);
+    /// Builds the combined NFA for the specs in `ss` with the chosen
+    /// construction; consumes (drains) the vector, like `build_from_specs`.
+    pub fn build_from_specs_with(ss: &mut Vec<Spec>, construction: Construction) -> NFA {
+        match construction {
+            Construction::Thompson => NFABuilder::build_from_specs(ss),
+            Construction::Glushkov => NFABuilder::build_glushkov(ss)
+        }
+    }
+
+    /// Builds the NFA of `build_from_specs_with`, but fails if the DFA
+    /// built for an intersection or a complement exceeds `limit`, with
+    /// the index of its spec as the rule to blame.
+    pub fn build_from_specs_limited(ss: &mut Vec<Spec>, construction: Construction, limit: &DfaLimit) -> Result<NFA, StateExplosion> {
+        let mut arena = SpecArena::new();
+        let ids: Vec<SpecID> = ss.drain(..).map(|s| arena.from_spec(&s)).collect();
+        NFABuilder::build_from_arena_with(&arena, &ids, construction, limit)
+    }
+
+    /// Builds the combined position automaton (Glushkov construction) of
+    /// the specs in `ss`, consuming the vector.
+    ///
+    /// The automaton has no epsilon transitions, and one state for each
+    /// symbol position of the specs plus a start state. The state of a
+    /// position is entered by the label at that position, and the states
+    /// of the last positions of each spec are tagged with its index.
+    ///
+    /// Specs with intersections or complements have no positions; they
+    /// are built by Thompson's construction followed by the removal of
+    /// epsilon transitions instead.
+    pub fn build_glushkov(ss: &mut Vec<Spec>) -> NFA {
+        // cannot fail without limits
+        NFABuilder::build_from_specs_limited(ss, Construction::Glushkov, &DfaLimit::unlimited()).unwrap()
+    }
+
+    // the position automaton of the specs roots of arena, which have no
+    // intersection or complement
+    fn build_positions(arena: &SpecArena, roots: &[SpecID]) -> NFA {
+        let mut pos = Positions::new();
+        let lins: Vec<Linear> = roots.iter().map(|&id| pos.linearize_node(arena, id)).collect();
+
+        let mut builder = NFABuilder::new();
+        let start = builder.new_state();
+        for _ in 0..pos.labels.len() {
+            builder.new_state();
+        }
+
+        let mut first = BTreeSet::new();
+        for (rule, lin) in lins.iter().enumerate() {
+            first.extend(lin.first.iter().cloned());
+            if lin.nullable {
+                builder.set_rule(start, rule);
+            }
+            for &p in lin.last.iter() {
+                builder.set_rule(p + 1, rule);
+            }
+        }
+
+        let follow = mem::take(&mut pos.follow);
+        for (src, targets) in ::std::iter::once(first).chain(follow).enumerate() {
+            // Any positions must not be blocked by the other transitions
+            // of the state, which they are not in the spec
+            let explicit = targets.iter().any(|&q| pos.labels[q] != Label::Any);
+            for q in targets {
+                let label = match pos.labels[q] {
+                    Label::Any if explicit => Label::Class(complement(&Vec::new())),
+                    ref l => l.clone()
+                };
+                builder.add_transition(src, q + 1, label);
+            }
+        }
+
+        NFA::new(builder.states, start)
+    }
+
+    /// Builds the combined NFA for the specs `roots` of `arena`, like
+    /// `build_from_specs`. A node shared by several specs gets its own
+    /// states each time it is used.
+    pub fn build_from_arena(arena: &SpecArena, roots: &[SpecID]) -> NFA {
+        // cannot fail without limits
+        NFABuilder::build_from_arena_limited(arena, roots, &DfaLimit::unlimited()).unwrap()
+    }
+
+    /// Builds the NFA of `build_from_arena_limited` with the chosen
+    /// construction, like `build_from_specs_limited`.
+    pub fn build_from_arena_with(arena: &SpecArena, roots: &[SpecID], construction: Construction,
+                                 limit: &DfaLimit) -> Result<NFA, StateExplosion> {
+        if construction == Construction::Glushkov && !roots.iter().any(|&id| arena.has_boolean_ops(id)) {
+            return Ok(NFABuilder::build_positions(arena, roots));
+        }
+        let nfa = NFABuilder::build_from_arena_limited(arena, roots, limit)?;
+        Ok(match construction {
+            Construction::Thompson => nfa,
+            Construction::Glushkov => nfa.remove_epsilons()
+        })
+    }
+
+    /// Builds the NFA of `build_from_arena`, but fails if the DFA built
+    /// for an intersection or a complement exceeds `limit`, with the
+    /// index of its root as the rule to blame.
+    pub fn build_from_arena_limited(arena: &SpecArena, roots: &[SpecID], limit: &DfaLimit) -> Result<NFA, StateExplosion> {
+        let mut builder = NFABuilder::new();
+        let mut ns = Vec::with_capacity(roots.len());
+        for (rule, &id) in roots.iter().enumerate() {
+            let n = builder.build_node(arena, id, limit)
+                .map_err(|e| StateExplosion { rule: Some(rule), ..e })?;
+            ns.push(n);
+        }
+        let start = builder.fuse_nfas(ns);
+
+        Ok(NFA::new(builder.states, start))
+    }
+
+    fn build_node(&mut self, arena: &SpecArena, id: SpecID, limit: &DfaLimit) -> Result<NFAid, StateExplosion> {
+        Ok(match *arena.node(id) {
+            SpecNode::Single(ref l) => self.single(l.clone()),
+            SpecNode::Union(s1, s2) => {
+                let n1 = self.build_node(arena, s1, limit)?;
+                let n2 = self.build_node(arena, s2, limit)?;
                 self.union(n1, n2)
             },
-            Spec::Concat(s1, s2) => {
-                let n1 = self.build(*s1);
-                let n2 = self.build(*s2);
+            SpecNode::Concat(s1, s2) => {
+                let n1 = self.build_node(arena, s1, limit)?;
+                let n2 = self.build_node(arena, s2, limit)?;
                 self.concat(n1, n2)
             },
-            Spec::Star(s) => {
-                let n = self.build(*s);
+            SpecNode::Star(s) => {
+                let n = self.build_node(arena, s, limit)?;
                 self.star(n)
+            },
+            SpecNode::Repeat(s, min, max) => {
+                let mut copies = NodeCopies { builder: self, arena, node: s, limit };
+                rules::repeat_range(&mut copies, min, max)?
+            },
+            SpecNode::Group(i, s) => {
+                let n = self.build_node(arena, s, limit)?;
+                self.group(i, n)
+            },
+            SpecNode::Intersection(s1, s2) => {
+                let d1 = NFABuilder::operand_dfa(arena, s1, limit)?;
+                let d2 = NFABuilder::operand_dfa(arena, s2, limit)?;
+                self.embed(&d1.product_limited(&d2, SetOp::Intersection, limit)?.minimize())
+            },
+            SpecNode::Complement(s) => {
+                // no larger than the DFA of the operand, plus a state
+                let d = NFABuilder::operand_dfa(arena, s, limit)?;
+                self.embed(&d.complement().minimize())
             }
-        }
+        })
+    }
+
+    // the minimal DFA of an operand of an intersection or a complement
+    fn operand_dfa(arena: &SpecArena, id: SpecID, limit: &DfaLimit) -> Result<NFA, StateExplosion> {
+        let nfa = NFABuilder::build_from_arena_limited(arena, &[id], limit)?;
+        Ok(nfa.to_dfa_limited(limit)?.minimize())
+    }
+
+    fn build(&mut self, s: Spec) -> NFAid {
+        let mut arena = SpecArena::new();
+        let id = arena.from_spec(&s);
+        // cannot fail without limits
+        self.build_node(&arena, id, &DfaLimit::unlimited()).unwrap()
     }
 
     // fuse all nfas creating a new start state; return ID of new start state
     fn fuse_nfas(&mut self, ns: Vec<NFAid>) -> StateID {
         let nstart = self.new_state();
-        for n in ns {
+        for (rule, n) in ns.into_iter().enumerate() {
             self.add_transition(nstart, n.start, Label::Epsilon);
-            self.set_accepting(n.accept);
+            self.set_rule(n.accept, rule);
         }
 
         nstart
@@ -402,7 +1508,7 @@ impl NFABuilder {
         let res = self.states.len();
         let trs : Vec<Transition> = Vec::with_capacity(2);
         //let st = State { id: res, trans: trs, accept: false };
-        let st = State { trans: trs, accept: false };
+        let st = State { trans: trs, accept: false, rule: None, save: None };
 
         self.states.push(st);
         res
@@ -421,6 +1527,7 @@ impl NFABuilder {
     }
 
     // TODO: check for deletion
+    #[cfg(test)]
     fn get_state(&self, id: StateID) -> Option<&State> {
         self.states.get(id)
     }
@@ -430,10 +1537,21 @@ impl NFABuilder {
         st.accept = true;
     }
 
-    fn add_transition(&mut self, src_id: StateID, dst_id: StateID, label: Label) -> bool {
+    // make id accepting for rule; the lowest rule id has priority
+    fn set_rule(&mut self, id: StateID, rule: RuleID) {
+        let st = self.get_state_mut(id);
+        st.accept = true;
+        st.rule = match st.rule {
+            Some(r) if r < rule => Some(r),
+            _ => Some(rule)
+        };
+    }
+
+    /// Adds a transition, if both states exist.
+    pub fn add_transition(&mut self, src_id: StateID, dst_id: StateID, label: Label) -> bool {
         if self.check_id(src_id) && self.check_id(dst_id) {
             let src_st = self.get_state_mut(src_id);
-            let trans = Transition { label: label, target: dst_id };
+            let trans = Transition { label, target: dst_id };
             src_st.trans.push(trans);
             true
         }
@@ -460,7 +1578,7 @@ impl NFABuilder {
         self.add_transition(n1.accept, acc, Label::Epsilon);
         self.add_transition(n2.accept, acc, Label::Epsilon);
 
-        NFAid { start: start, accept: acc }
+        NFAid { start, accept: acc }
     }
 
     fn concat(&mut self, n1: NFAid, n2: NFAid) -> NFAid {
@@ -482,9 +1600,43 @@ impl NFABuilder {
         // loop transition for any number of occurrences
         self.add_transition(n.accept, n.start, Label::Epsilon);
 
-        NFAid { start: start, accept: acc }
+        NFAid { start, accept: acc }
     }
 
+    // copies the states of dfa, with a new accepting state reached by
+    // epsilon transitions from its accepting states
+    fn embed(&mut self, dfa: &NFA) -> NFAid {
+        let base = self.states.len();
+        for _ in 0..dfa.num_states() {
+            self.new_state();
+        }
+        let acc = self.new_state();
+
+        for (s, st) in dfa.states.iter().enumerate() {
+            for t in st.trans.iter() {
+                self.add_transition(base + s, base + t.target, t.label.clone());
+            }
+            if st.accept {
+                self.add_transition(base + s, acc, Label::Epsilon);
+            }
+        }
+
+        NFAid { start: base + dfa.start, accept: acc }
+    }
+
+    // states around n that save the positions where group i starts and
+    // ends, in slots 2 * i and 2 * i + 1
+    fn group(&mut self, i: usize, n: NFAid) -> NFAid {
+        let open = self.new_state();
+        let close = self.new_state();
+        self.get_state_mut(open).save = Some(2 * i);
+        self.get_state_mut(close).save = Some(2 * i + 1);
+
+        self.add_transition(open, n.start, Label::Epsilon);
+        self.add_transition(n.accept, close, Label::Epsilon);
+
+        NFAid { start: open, accept: close }
+    }
 }
 
 #[test]
@@ -542,8 +1694,6 @@ fn test_union() {
 
 #[test]
 fn to_dfa() {
-    use nfa::Label::{Epsilon, Any};
-
     let spec = Spec::union(Spec::single(Label::Symbol('a')), Spec::single(Label::Symbol('b')));
     let nfa = NFABuilder::build_from_spec(*spec);
     nfa.dot_output("todfa_nfa.dot");
@@ -558,6 +1708,7 @@ from card: https://trello.com/c/q4DyS8jj
 */
 #[test]
 fn test_nfa_dfa() {
+    use equivalence::equivalent;
     use regex_syntax::Expr;
     use rules;
 
@@ -570,6 +1721,7 @@ fn test_nfa_dfa() {
     assert!(dfa1.simulate("aaaaa"));
     assert!(!nfa1.simulate("aa"));
     assert!(!dfa1.simulate("aa"));
+    assert_eq!(equivalent(&nfa1, &dfa1), Ok(()));
 
     //test 2
     let re2 = Expr::parse(r"ab|cd").unwrap();
@@ -582,6 +1734,7 @@ fn test_nfa_dfa() {
     assert!(dfa2.simulate("cd"));
     assert!(!nfa2.simulate("abcd"));
     assert!(!dfa2.simulate("abcd"));
+    assert_eq!(equivalent(&nfa2, &dfa2), Ok(()));
 
     //test 3
     let re3 = Expr::parse(r"ab*").unwrap();
@@ -599,12 +1752,12 @@ fn test_nfa_dfa() {
     assert!(dfa3.simulate("a"));
     assert!(!nfa3.simulate("abbabbbb"));
     assert!(!dfa3.simulate("abbabbbb"));
+    assert_eq!(equivalent(&nfa3, &dfa3), Ok(()));
 
 }
 
 #[test]
 fn test_to_dfa() {
-    use nfa::Label::*;
     use regex_syntax::Expr;
     use rules;
 
@@ -694,3 +1847,457 @@ fn test_build_specs() {
     assert!(n.simulate("a"));
     assert!(!n.simulate("baa"));
 }
+
+#[test]
+fn test_longest_match() {
+    use nfa::Label::*;
+
+    let kw = Spec::concat(Spec::single(Symbol('i')), Spec::single(Symbol('f')));
+    let id = Spec::star(Spec::union(Spec::single(Symbol('i')), Spec::single(Symbol('f'))));
+    let any = Spec::single(Any);
+
+    let mut v : Vec<Spec> = vec![*kw, *id, *any];
+    let n = NFABuilder::build_from_specs(&mut v);
+    let d = n.to_dfa();
+
+    for a in [&n, &d].iter() {
+        assert_eq!(a.longest_match("if"), Some((0, 2)));
+        assert_eq!(a.longest_match("iff x"), Some((1, 3)));
+        assert_eq!(a.longest_match("x"), Some((2, 1)));
+        assert_eq!(a.longest_match(""), Some((1, 0)));
+    }
+}
+
+#[test]
+fn test_to_dfa_any() {
+    use nfa::Label::*;
+
+    // a.|b
+    let sp = Spec::union(Spec::concat(Spec::single(Symbol('a')), Spec::single(Any)),
+                         Spec::single(Symbol('b')));
+    let nfa = NFABuilder::build_from_spec(*sp);
+    let dfa = nfa.to_dfa();
+    for a in [&nfa, &dfa].iter() {
+        assert!(a.simulate("ab"));
+        assert!(a.simulate("aa"));
+        assert!(a.simulate("b"));
+        assert!(!a.simulate("a"));
+        assert!(!a.simulate("x"));
+        assert!(!a.simulate("abb"));
+    }
+
+    // a.*b, where the DFA has both a Symbol and an Any transition
+    // from the same state
+    let sp = Spec::concat(Spec::concat(Spec::single(Symbol('a')), Spec::star(Spec::single(Any))),
+                          Spec::single(Symbol('b')));
+    let nfa = NFABuilder::build_from_spec(*sp);
+    let dfa = nfa.to_dfa();
+    for w in ["ab", "axb", "abb", "abxb", "a", "ax", "abx", "b"].iter() {
+        assert_eq!(dfa.simulate(w), nfa.simulate(w), "{}", w);
+    }
+    assert!(dfa.simulate("abxb"));
+    assert!(!dfa.simulate("abx"));
+}
+
+#[test]
+fn test_to_dfa_start() {
+    use nfa::Label::*;
+
+    // the start state of a DFA can be accepting
+    let sp = Spec::star(Spec::single(Symbol('a')));
+    let dfa = NFABuilder::build_from_spec(*sp).to_dfa();
+    assert!(dfa.simulate(""));
+    assert_eq!(dfa.longest_match("b"), Some((0, 0)));
+}
+
+#[test]
+fn test_rule_usage() {
+    use nfa::Label::*;
+
+    // if | (i|f)+ | if | f | .
+    let kw = || Spec::concat(Spec::single(Symbol('i')), Spec::single(Symbol('f')));
+    let id = Spec::concat(Spec::union(Spec::single(Symbol('i')), Spec::single(Symbol('f'))),
+                          Spec::star(Spec::union(Spec::single(Symbol('i')), Spec::single(Symbol('f')))));
+    let mut v : Vec<Spec> = vec![*kw(), *id, *kw(), *Spec::single(Symbol('f')), *Spec::single(Any)];
+    let usage = NFABuilder::build_from_specs(&mut v).rule_usage(5);
+
+    assert!(usage[0].wins);
+    assert_eq!(usage[0].example, Some(("if".to_string(), 0)));
+    assert!(usage[1].wins);
+    assert_eq!(usage[1].example, Some(("f".to_string(), 1)));
+    assert!(!usage[2].wins);
+    assert_eq!(usage[2].example, Some(("if".to_string(), 0)));
+    assert!(!usage[3].wins);
+    assert_eq!(usage[3].example, Some(("f".to_string(), 1)));
+    assert!(usage[4].wins);
+    assert_eq!(usage[4].example, Some(("a".to_string(), 4)));
+}
+
+#[test]
+fn test_accepts_empty() {
+    use nfa::Label::*;
+
+    assert!(NFABuilder::build_from_spec(Spec::Single(Epsilon)).accepts_empty());
+    assert!(!NFABuilder::build_from_spec(Spec::Single(Any)).accepts_empty());
+    assert!(NFABuilder::build_from_spec(*Spec::star(Spec::single(Symbol('a')))).accepts_empty());
+    let sp = Spec::concat(Spec::star(Spec::single(Symbol('a'))), Spec::single(Symbol('b')));
+    assert!(!NFABuilder::build_from_spec(*sp).accepts_empty());
+}
+
+#[test]
+fn test_shortest_common() {
+    use nfa::Label::*;
+
+    // (a|b)*b and a.*
+    let sp1 = Spec::concat(Spec::star(Spec::union(Spec::single(Symbol('a')), Spec::single(Symbol('b')))),
+                           Spec::single(Symbol('b')));
+    let sp2 = Spec::concat(Spec::single(Symbol('a')), Spec::star(Spec::single(Any)));
+    let d1 = NFABuilder::build_from_spec(*sp1).to_dfa();
+    let d2 = NFABuilder::build_from_spec(*sp2).to_dfa();
+    assert_eq!(d1.shortest_common(&d2), Some("ab".to_string()));
+    assert_eq!(d2.shortest_common(&d2), Some("a".to_string()));
+
+    let sp3 = Spec::concat(Spec::single(Symbol('b')), Spec::single(Any));
+    let d3 = NFABuilder::build_from_spec(*sp3).to_dfa();
+    assert_eq!(d1.shortest_common(&d3), Some("bb".to_string()));
+    assert_eq!(d2.shortest_common(&d3), None);
+}
+
+#[test]
+fn test_shortest_accepted() {
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let nfa = NFABuilder::build_from_specs(&mut vec![spec(r"while|if"), spec(r"[a-z]+[0-9]"), spec(r"ab*c"),
+                                                     spec(r"x.y"), spec(r"[x-z]")]);
+    assert_eq!(nfa.shortest_accepted(), Some("x".to_string()));
+    assert_eq!(nfa.shortest_accepted_by(0), Some("if".to_string()));
+    assert_eq!(nfa.shortest_accepted_by(1), Some("a0".to_string()));
+    assert_eq!(nfa.shortest_accepted_by(2), Some("ac".to_string()));
+    assert_eq!(nfa.shortest_accepted_by(5), None);
+    assert!(!nfa.is_empty());
+
+    // Any only takes the characters without another transition
+    let w = nfa.shortest_accepted_by(3).unwrap();
+    assert!(w.len() == 3 && w.starts_with('x') && w.ends_with('y'), "{}", w);
+    assert_eq!(nfa.longest_match(&w), Some((3, 3)));
+
+    // in a DFA the states are tagged with the rule that wins
+    let dfa = nfa.to_dfa();
+    assert_eq!(dfa.shortest_accepted_by(1), Some("a0".to_string()));
+    assert_eq!(dfa.shortest_accepted(), Some("x".to_string()));
+    let shadowed = NFABuilder::build_from_specs(&mut vec![spec(r"[a-z]+"), spec(r"if")]).to_dfa();
+    assert_eq!(shadowed.shortest_accepted_by(1), None);
+
+    let empty = NFABuilder::build_from_spec(spec(r"a")).to_dfa().difference(&NFABuilder::build_from_spec(spec(r"a|b")).to_dfa());
+    assert!(empty.is_empty());
+    assert_eq!(empty.shortest_accepted(), None);
+    assert!(!NFABuilder::build_from_spec(spec(r"a*")).is_empty());
+}
+
+#[test]
+fn test_product() {
+    use regex_syntax::Expr;
+    use rules;
+
+    let dfa = |p| NFABuilder::build_from_spec(rules::regex_to_nfa_spec(&Expr::parse(p).unwrap())).to_dfa();
+    let ident = dfa(r"[a-z][a-z0-9]*");
+    let keywords = dfa(r"if|else|while");
+    let digits = dfa(r"[0-9a-f]+");
+
+    // identifiers but not keywords
+    let names = ident.difference(&keywords);
+    for &(w, accepted) in [("x", true), ("if", false), ("iff", true), ("els", true), ("else", false),
+                           ("while", false), ("w1", true), ("9", false), ("", false)].iter() {
+        assert_eq!(names.simulate(w), accepted, "{}", w);
+    }
+
+    let words = ["", "a", "if", "abc", "cafe", "x9", "0", "99", "é", "a!", "while"];
+    for w in words.iter() {
+        let (a, b) = (ident.simulate(w), digits.simulate(w));
+        assert_eq!(ident.intersection(&digits).simulate(w), a && b, "{}", w);
+        assert_eq!(ident.union(&digits).simulate(w), a || b, "{}", w);
+        assert_eq!(digits.difference(&ident).simulate(w), b && !a, "{}", w);
+        assert_eq!(ident.complement().simulate(w), !a, "{}", w);
+        assert_eq!(ident.complement().complement().simulate(w), a, "{}", w);
+    }
+
+    // the rule of the first automaton is kept
+    let tagged = NFABuilder::build_from_specs(&mut vec![rules::regex_to_nfa_spec(&Expr::parse("if").unwrap()),
+                                                        rules::regex_to_nfa_spec(&Expr::parse("[a-z]+").unwrap())]).to_dfa();
+    let u = tagged.union(&digits);
+    assert_eq!(u.longest_match("if"), Some((0, 2)));
+    assert_eq!(u.longest_match("ab!"), Some((1, 2)));
+    assert_eq!(u.longest_match("00"), Some((0, 2)));
+}
+
+#[test]
+fn test_minimize() {
+    use nfa::Label::*;
+
+    // (a|b)*b: the subset construction gives 4 states, the minimal DFA has 2
+    let sp = Spec::concat(Spec::star(Spec::union(Spec::single(Symbol('a')), Spec::single(Symbol('b')))),
+                          Spec::single(Symbol('b')));
+    let dfa = NFABuilder::build_from_spec(*sp).to_dfa();
+    let min = dfa.minimize();
+    assert!(min.num_states() < dfa.num_states());
+    assert_eq!(min.num_states(), 2);
+    for w in ["", "a", "b", "ab", "ba", "abab", "bbb", "c", "bc"].iter() {
+        assert_eq!(min.simulate(w), dfa.simulate(w));
+    }
+
+    // states accepting different rules are not merged
+    let mut v : Vec<Spec> = vec![*Spec::single(Symbol('a')), *Spec::single(Any)];
+    let min2 = NFABuilder::build_from_specs(&mut v).to_dfa().minimize();
+    assert_eq!(min2.num_states(), 3);
+    assert_eq!(min2.longest_match("a"), Some((0, 1)));
+    assert_eq!(min2.longest_match("b"), Some((1, 1)));
+}
+
+// the NFA of a few hundred keyword rules, as in the lexer of a large
+// language, where many rules share each DFA state
+#[cfg(test)]
+fn keywords_nfa(n: usize) -> NFA {
+    use regex_syntax::Expr;
+    use rules;
+
+    let mut specs: Vec<Spec> = (0..n).map(|i| rules::regex_to_nfa_spec(&Expr::parse(&format!("kw{}", i)).unwrap())).collect();
+    specs.push(rules::regex_to_nfa_spec(&Expr::parse(r"[a-z][a-z0-9]*").unwrap()));
+    NFABuilder::build_from_specs(&mut specs)
+}
+
+#[test]
+fn test_to_dfa_keywords() {
+    let nfa = keywords_nfa(300);
+    let dfa = nfa.to_dfa();
+
+    for w in ["kw0", "kw42", "kw299", "kw300", "kw", "kw1x", "x", "0"].iter() {
+        assert_eq!(dfa.longest_match(w), nfa.longest_match(w), "{}", w);
+    }
+    assert_eq!(dfa.longest_match("kw42"), Some((42, 4)));
+    assert_eq!(dfa.longest_match("kw300"), Some((300, 5)));
+}
+
+// benchmark of the subset construction on keyword rules, against the
+// same construction with DFA states keyed by the string of their sorted
+// NFA states, as they were before; run with
+// cargo test --release bench_to_dfa_keywords -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_to_dfa_keywords() {
+    use std::time::Duration;
+    use std::time::Instant;
+
+    fn string_key_state(nfa: &NFA, set: &StateSet, builder: &mut NFABuilder, sets: &mut Vec<Vec<StateID>>,
+                        state_map: &mut HashMap<String, StateID>) -> StateID {
+        let states = sorted_states(set);
+        let key: String = states.iter().map(|s| format!("{} ", s)).collect();
+        if let Some(&state) = state_map.get(&key) {
+            return state;
+        }
+
+        let state = builder.new_state();
+        if set.iter().any(|&i| nfa.states[i].accept) {
+            builder.set_accepting(state);
+        }
+        if let Some(rule) = nfa.state_set_rule(set) {
+            builder.set_rule(state, rule);
+        }
+        sets.push(states);
+        state_map.insert(key, state);
+        state
+    }
+
+    fn to_dfa_string_keys(nfa: &NFA) -> NFA {
+        let mut builder = NFABuilder::new();
+        let mut sets: Vec<Vec<StateID>> = Vec::new();
+        let mut state_map: HashMap<String, StateID> = HashMap::new();
+        let classes = nfa.char_classes();
+
+        let mut cur = nfa.start_set();
+        let mut next = StateSet::new(nfa.states.len());
+        let start = string_key_state(nfa, &cur, &mut builder, &mut sets, &mut state_map);
+        let mut state = 0;
+        while state < sets.len() {
+            cur.clear();
+            for &s in sets[state].iter() {
+                cur.insert(s);
+            }
+            for k in 0..classes.len() {
+                let c = match classes.representative(k) {
+                    Some(c) => c,
+                    None => continue
+                };
+                nfa.step_set(&cur, c, &mut next);
+                if next.is_empty() {
+                    continue;
+                }
+                let new_state = string_key_state(nfa, &next, &mut builder, &mut sets, &mut state_map);
+                builder.add_transition(state, new_state, classes.label(k));
+            }
+            state += 1;
+        }
+
+        NFA::new(builder.states, start)
+    }
+
+    fn time<F: Fn() -> NFA>(f: F) -> (NFA, Duration) {
+        let t = Instant::now();
+        let mut dfa = f();
+        for _ in 1..10 {
+            dfa = f();
+        }
+        (dfa, t.elapsed() / 10)
+    }
+
+    for &n in [100, 300, 1000].iter() {
+        let nfa = keywords_nfa(n);
+        let (dfa, vectors) = time(|| nfa.to_dfa());
+        let (old, strings) = time(|| to_dfa_string_keys(&nfa));
+        assert_eq!(dfa.num_states(), old.num_states());
+        println!("{} keywords: {} NFA states, {} DFA states, {:?} with vector keys, {:?} with string keys",
+                 n, nfa.num_states(), dfa.num_states(), vectors, strings);
+    }
+}
+
+#[test]
+fn test_to_dfa_limited() {
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let nfa = NFABuilder::build_from_specs(&mut vec![spec(r"if"), spec(r"(a|b)*a(a|b){12}"), spec(r"[a-z]+")]);
+
+    let limit = DfaLimit { max_states: 1000, max_bytes: usize::MAX };
+    let err = nfa.to_dfa_limited(&limit).err().unwrap();
+    assert_eq!(err.states, 1001);
+    assert_eq!(err.rule, Some(1));
+
+    let limit = DfaLimit { max_states: usize::MAX, max_bytes: 100000 };
+    let err = nfa.to_dfa_limited(&limit).err().unwrap();
+    assert!(err.bytes > 100000);
+    assert_eq!(err.rule, Some(1));
+
+    let small = NFABuilder::build_from_specs(&mut vec![spec(r"if"), spec(r"[a-z]+")]);
+    let dfa = small.to_dfa_limited(&DfaLimit::default()).unwrap();
+    assert_eq!(dfa.num_states(), small.to_dfa().num_states());
+}
+
+#[test]
+fn test_build_limited() {
+    use pattern;
+
+    let spec = |p| pattern::parse(p).unwrap().to_spec();
+    let specs = vec![spec(r"if"), spec(r"~((a|b)*a(a|b){12})"), spec(r"[a-z]+&~(x|y)")];
+    let limit = DfaLimit { max_states: 1000, max_bytes: usize::MAX };
+
+    // the DFA of the operand of ~ is too large
+    for &construction in [Construction::Thompson, Construction::Glushkov].iter() {
+        let err = NFABuilder::build_from_specs_limited(&mut specs.clone(), construction, &limit).err().unwrap();
+        assert_eq!(err.rule, Some(1));
+        assert!(err.states > 1000);
+    }
+
+    // so is the product of an intersection, from small operands
+    let mut product = vec![spec(r"[a-d]*a[a-d]{2}&[a-d]*c[a-d]{2}")];
+    let limit = DfaLimit { max_states: 20, max_bytes: usize::MAX };
+    let err = NFABuilder::build_from_specs_limited(&mut product.clone(), Construction::Thompson, &limit).err().unwrap();
+    assert_eq!(err.rule, Some(0));
+
+    let nfa = NFABuilder::build_from_specs_limited(&mut specs.clone()[2..].to_vec(), Construction::Thompson,
+                                                   &DfaLimit::default()).unwrap();
+    assert!(nfa.simulate("abc") && !nfa.simulate("x"));
+    assert!(NFABuilder::build_from_specs_limited(&mut product, Construction::Thompson, &DfaLimit::default()).is_ok());
+}
+
+#[test]
+fn test_remove_epsilons() {
+    use equivalence::equivalent;
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let nfas = [NFABuilder::build_from_spec(spec(r"(ab|c)*d?")),
+                NFABuilder::build_from_specs(&mut vec![spec(r"if"), spec(r"[a-z]+"), spec(r"."), spec(r"a.*z")]),
+                NFABuilder::build_from_specs(&mut vec![spec(r"x|."), spec(r"[^x]y")])];
+    let words = ["", "ab", "abcab", "abd", "d", "if", "iff", "i", "!", "ax!z", "az", "x", "xy", "!y", "é", "yy"];
+
+    for nfa in nfas.iter() {
+        let free = nfa.remove_epsilons();
+        assert!(free.states.iter().all(|st| st.trans.iter().all(|t| t.label != Label::Epsilon)));
+        assert!(free.num_states() < nfa.num_states());
+        for w in words.iter() {
+            assert_eq!(free.longest_match(w), nfa.longest_match(w), "{}", w);
+            assert_eq!(free.simulate(w), nfa.simulate(w), "{}", w);
+        }
+        assert_eq!(equivalent(&free, nfa), Ok(()));
+        assert_eq!(equivalent(&free.to_dfa().minimize(), nfa), Ok(()));
+    }
+
+    assert_eq!(complement(&[('\0', 'b'), ('a', 'z')]), vec![('{', '\u{D7FF}'), ('\u{E000}', '\u{10FFFF}')]);
+    assert_eq!(complement(&[('\0', '\u{10FFFF}')]), vec![]);
+}
+
+// a random spec over a few symbols, for comparing constructions
+#[cfg(test)]
+fn random_spec(seed: &mut u64, depth: u32) -> Spec {
+    *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    let r = (*seed >> 33) % if depth == 0 { 4 } else { 8 };
+    match r {
+        0 => Spec::Single(Label::Symbol('a')),
+        1 => Spec::Single(Label::Symbol('b')),
+        2 => Spec::Single(Label::Class(vec![('b', 'c')])),
+        3 => Spec::Single(if depth.is_multiple_of(2) { Label::Any } else { Label::Epsilon }),
+        4 | 5 => Spec::Concat(Box::new(random_spec(seed, depth - 1)), Box::new(random_spec(seed, depth - 1))),
+        6 => Spec::Union(Box::new(random_spec(seed, depth - 1)), Box::new(random_spec(seed, depth - 1))),
+        _ => Spec::Star(Box::new(random_spec(seed, depth - 1)))
+    }
+}
+
+#[test]
+fn test_glushkov_arena() {
+    use regex_syntax::Expr;
+
+    // the copies of a shared or repeated node get their own positions
+    let mut arena = SpecArena::new();
+    let ids: Vec<SpecID> = [r"(ab|c){2,5}d", r"[a-d]{3}", r"(ab|c)*"].iter()
+        .map(|p| rules::regex_to_spec_id(&mut arena, &Expr::parse(p).unwrap())).collect();
+    let nfa = NFABuilder::build_from_arena_with(&arena, &ids, Construction::Glushkov, &DfaLimit::unlimited()).unwrap();
+    let specs: Vec<Spec> = ids.iter().map(|&id| arena.to_spec(id)).collect();
+    let thompson = NFABuilder::build_from_arena(&arena, &ids);
+
+    assert_eq!(nfa.num_states(), specs.iter().map(|s| s.positions()).sum::<usize>() + 1);
+    for w in ["abcd", "ccccccd", "ababababcd", "abd", "abc", "cab", "", "abab"].iter() {
+        assert_eq!(nfa.longest_match(w), thompson.longest_match(w), "{}", w);
+    }
+}
+
+#[test]
+fn test_glushkov() {
+    use equivalence::equivalent;
+
+    let mut seed = 42;
+    let mut words = vec![String::new()];
+    for len in 1..5 {
+        for n in 0..4usize.pow(len) {
+            words.push((0..len).map(|i| ['a', 'b', 'c', 'd'][n / 4usize.pow(i) % 4]).collect());
+        }
+    }
+
+    for _ in 0..200 {
+        let specs: Vec<Spec> = (0..3).map(|_| random_spec(&mut seed, 4)).collect();
+        let npositions: usize = specs.iter().map(|s| s.positions()).sum();
+        let thompson = NFABuilder::build_from_specs_with(&mut specs.clone(), Construction::Thompson);
+        let glushkov = NFABuilder::build_from_specs_with(&mut specs.clone(), Construction::Glushkov);
+
+        assert_eq!(glushkov.num_states(), npositions + 1);
+        assert!(glushkov.states.iter().all(|st| st.trans.iter().all(|t| t.label != Label::Epsilon)));
+        for w in words.iter() {
+            assert_eq!(glushkov.simulate(w), thompson.simulate(w), "{:?} on {:?}", specs, w);
+            assert_eq!(glushkov.longest_match(w), thompson.longest_match(w), "{:?} on {:?}", specs, w);
+        }
+        assert_eq!(equivalent(&glushkov, &thompson), Ok(()), "{:?}", specs);
+        assert_eq!(equivalent(&glushkov.to_dfa(), &thompson), Ok(()), "{:?}", specs);
+    }
+}
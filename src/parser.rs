@@ -1,7 +1,21 @@
 use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
 
-fn build_rules(path: &str) -> HashMap<String, String> {
+use arena::SpecArena;
+use arena::SpecID;
+use nfa::Construction;
+use nfa::DfaLimit;
+use nfa::NFA;
+use nfa::NFABuilder;
+use nfa::RuleID;
+use nfa::StateExplosion;
+use pattern;
+use rules::Rule;
+use rules::EofRule;
+use utf8;
+
+fn build_rules(_path: &str) -> HashMap<String, String> {
 	let mut map_rules = HashMap::new();
 
 	//ASCII character classes
@@ -26,7 +40,9 @@ fn build_rules(path: &str) -> HashMap<String, String> {
 // s  : String 
 // *s : str (via Deref<Target=str>)
 // &*s: &st
-fn expand_definitions(s: &str, map: HashMap<String, String>) -> String {
+//
+// returns the name of the first undefined definition as error
+fn expand_definitions(s: &str, map: &HashMap<String, String>) -> Result<String, String> {
 	let re = Regex::new(r"(\{[:alpha:]{1,}\})").unwrap();
 	let mut text = String::from(s);
 
@@ -34,20 +50,373 @@ fn expand_definitions(s: &str, map: HashMap<String, String>) -> String {
 		let tmp = cap.at(1).unwrap_or(""); 
 		let key = &tmp[1..tmp.len()-1];
 
-		text = text.replace(tmp, &map[key]);
+		match map.get(key) {
+			Some(def) => text = text.replace(tmp, def),
+			None => return Err(key.to_string())
+		}
+	}
+	Ok(text)
+}
+
+/// A start condition declared with `%s` (inclusive) or `%x` (exclusive).
+pub struct StartCondition {
+	pub name: String,
+	pub exclusive: bool,
+}
+
+/// A lexer specification read from a spec file.
+pub struct LexSpec {
+	/// Declared start conditions; `INITIAL` is always the first one.
+	pub conditions: Vec<StartCondition>,
+	/// Rules in priority order; a `RuleID` is an index in this vector.
+	pub rules: Vec<Rule>,
+	pub eof_rules: Vec<EofRule>,
+	/// Code after the second `%%`, copied verbatim to the generated lexer.
+	pub user_code: String,
+}
+
+impl LexSpec {
+	/// Returns the ids of the rules active in start condition `cond`,
+	/// in priority order.
+	pub fn active_rules(&self, cond: usize) -> Vec<RuleID> {
+		let exclusive = self.conditions[cond].exclusive;
+		(0..self.rules.len()).filter(|&r| {
+			let conds = &self.rules[r].conditions;
+			conds.contains(&cond) || (conds.is_empty() && !exclusive)
+		}).collect()
+	}
+
+	/// Builds the combined NFA for the rules active in start condition
+	/// `cond`; its rule tags are indices in `active_rules(cond)`.
+	pub fn condition_nfa(&self, cond: usize) -> NFA {
+		// cannot fail without limits
+		self.condition_nfa_limited(cond, false, Construction::Thompson, &DfaLimit::unlimited()).unwrap()
+	}
+
+	/// Builds the combined byte-level NFA for the rules active in start
+	/// condition `cond`, which reads the UTF-8 encoding of the input.
+	pub fn condition_utf8_nfa(&self, cond: usize) -> NFA {
+		self.condition_nfa_with(cond, true, Construction::Thompson)
+	}
+
+	/// Builds the NFA of `condition_utf8_nfa` if `utf8` is set, or else
+	/// of `condition_nfa`, with the given construction.
+	pub fn condition_nfa_with(&self, cond: usize, utf8: bool, construction: Construction) -> NFA {
+		// cannot fail without limits
+		self.condition_nfa_limited(cond, utf8, construction, &DfaLimit::unlimited()).unwrap()
+	}
+
+	/// Builds the NFA of `condition_nfa_with`, but fails if the DFA built
+	/// for an intersection or a complement in a rule exceeds `limit`.
+	pub fn condition_nfa_limited(&self, cond: usize, utf8: bool, construction: Construction,
+	                             limit: &DfaLimit) -> Result<NFA, StateExplosion> {
+		let mut arena = SpecArena::new();
+		let ids: Vec<SpecID> = self.active_rules(cond).iter().map(|&r| {
+			let id = self.rules[r].regex.to_spec_id(&mut arena);
+			if utf8 { utf8::utf8_spec_id(&mut arena, id) } else { id }
+		}).collect();
+		NFABuilder::build_from_arena_with(&arena, &ids, construction, limit)
+	}
+
+	/// Returns the action to run at end of input in start condition `cond`.
+	pub fn eof_action(&self, cond: usize) -> Option<&str> {
+		self.eof_rules.iter().find(|r| r.conditions.contains(&cond))
+			.or(self.eof_rules.iter().find(|r| r.conditions.is_empty()))
+			.map(|r| &r.action[..])
+	}
+
+	pub fn condition_id(&self, name: &str) -> Option<usize> {
+		self.conditions.iter().position(|c| c.name == name)
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+	pub line: usize,
+	pub message: String,
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "line {}: {}", self.line, self.message)
+	}
+}
+
+fn error<T>(line: usize, message: String) -> Result<T, ParseError> {
+	Err(ParseError { line, message })
+}
+
+const EOF_PATTERN: &str = "<<EOF>>";
+
+// splits a rule line into its start condition list (if any), its pattern and
+// its action; the pattern ends at the first whitespace outside of a class
+fn split_rule(line: &str) -> (Option<&str>, &str, &str) {
+	let mut conds = None;
+	let mut rest = line;
+
+	if line.starts_with('<') && !line.starts_with(EOF_PATTERN) {
+		if let Some(end) = line.find('>') {
+			conds = Some(&line[1..end]);
+			rest = &line[end + 1..];
+		}
+	}
+
+	let mut in_class = false;
+	let mut escaped = false;
+	let mut end = rest.len();
+	for (i, c) in rest.char_indices() {
+		if escaped {
+			escaped = false;
+		} else if c == '\\' {
+			escaped = true;
+		} else if c == '[' {
+			in_class = true;
+		} else if c == ']' {
+			in_class = false;
+		} else if c.is_whitespace() && !in_class {
+			end = i;
+			break;
+		}
+	}
+
+	(conds, &rest[..end], rest[end..].trim())
+}
+
+// resolves a list of start condition names like "A,B" or "*"
+fn resolve_conditions(list: &str, conditions: &[StartCondition], line: usize) -> Result<Vec<usize>, ParseError> {
+	if list.trim() == "*" {
+		return Ok((0..conditions.len()).collect());
+	}
+
+	let mut res = Vec::new();
+	for name in list.split(',').map(|n| n.trim()) {
+		match conditions.iter().position(|c| c.name == name) {
+			Some(id) => res.push(id),
+			None => return error(line, format!("undeclared start condition '{}'", name))
+		}
+	}
+	Ok(res)
+}
+
+fn brace_balance(s: &str) -> i32 {
+	s.chars().fold(0, |n, c| match c {
+		'{' => n + 1,
+		'}' => n - 1,
+		_ => n
+	})
+}
+
+/// Parses the text of a lexer specification.
+///
+/// The specification has a definitions section, a rules section and an
+/// optional user code section, separated by lines containing `%%`.
+/// Definitions are lines `name regex`, start condition declarations
+/// `%s NAME...` or `%x NAME...`, and options `%option NAME...`; the only
+/// option is `set-operators`, which makes `&` and `~` the intersection
+/// and complement operators in patterns instead of literal characters. Each rule is a pattern, optionally
+/// preceded by a start condition list like `<A,B>` or `<*>`, followed by
+/// an action. The pattern `<<EOF>>` introduces an action that runs when
+/// the input is exhausted. Actions that start with `{` may span several
+/// lines.
+pub fn parse_spec(text: &str) -> Result<LexSpec, ParseError> {
+	let mut defs = build_rules("");
+	let mut conditions = vec![StartCondition { name: "INITIAL".to_string(), exclusive: false }];
+	let mut rules = Vec::new();
+	let mut eof_rules: Vec<EofRule> = Vec::new();
+	let mut user_code = String::new();
+	let mut section = 0;
+	let mut set_operators = false;
+
+	let mut lines = text.lines().enumerate().map(|(i, l)| (i + 1, l));
+	while let Some((lineno, line)) = lines.next() {
+		if line.trim() == "%%" {
+			section += 1;
+			continue;
+		}
+
+		match section {
+			0 => {
+				let line = line.trim();
+				if line.is_empty() || line.starts_with("//") {
+					continue;
+				}
+				let mut words = line.split_whitespace();
+				let first = words.next().unwrap();  // safe to unwrap because line is not empty
+				if first == "%option" {
+					for name in words {
+						match name {
+							"set-operators" => set_operators = true,
+							_ => return error(lineno, format!("unknown option '{}'", name))
+						}
+					}
+				} else if first == "%s" || first == "%x" {
+					for name in words {
+						if conditions.iter().any(|c| c.name == name) {
+							return error(lineno, format!("start condition '{}' declared twice", name));
+						}
+						conditions.push(StartCondition { name: name.to_string(), exclusive: first == "%x" });
+					}
+				} else {
+					if !first.chars().all(|c| c.is_alphabetic()) {
+						return error(lineno, format!("invalid definition name '{}'", first));
+					}
+					let def = match expand_definitions(line[first.len()..].trim(), &defs) {
+						Ok(d) => d,
+						Err(name) => return error(lineno, format!("undefined definition '{}'", name))
+					};
+					defs.insert(first.to_string(), format!("(?:{})", def));
+				}
+			},
+			1 => {
+				if line.trim().is_empty() || line.trim().starts_with("//") {
+					continue;
+				}
+				let (conds, pattern, action) = split_rule(line.trim());
+				let conds = match conds {
+					Some(list) => resolve_conditions(list, &conditions, lineno)?,
+					None => Vec::new()
+				};
+
+				let mut action = action.to_string();
+				if action.starts_with('{') {
+					let mut depth = brace_balance(&action);
+					while depth > 0 {
+						match lines.next() {
+							Some((_, l)) => {
+								action.push('\n');
+								action.push_str(l);
+								depth += brace_balance(l);
+							},
+							None => return error(lineno, "unterminated action".to_string())
+						}
+					}
+				}
+
+				if pattern == EOF_PATTERN {
+					let taken = eof_rules.iter().any(|r| {
+						if conds.is_empty() {
+							r.conditions.is_empty()
+						} else {
+							r.conditions.iter().any(|c| conds.contains(c))
+						}
+					});
+					if taken {
+						return error(lineno, "multiple <<EOF>> rules for the same start condition".to_string());
+					}
+					eof_rules.push(EofRule { action, conditions: conds });
+					continue;
+				}
+
+				let expanded = match expand_definitions(pattern, &defs) {
+					Ok(p) => p,
+					Err(name) => return error(lineno, format!("undefined definition '{}'", name))
+				};
+				let parsed = if set_operators {
+					pattern::parse(&expanded)
+				} else {
+					pattern::parse_without_operators(&expanded)
+				};
+				let regex = match parsed {
+					Ok(re) => re,
+					Err(e) => return error(lineno, format!("invalid pattern '{}': {}", pattern, e))
+				};
+				rules.push(Rule { pattern: pattern.to_string(), regex, action, conditions: conds });
+			},
+			_ => {
+				user_code.push_str(line);
+				user_code.push('\n');
+			}
+		}
 	}
-	text
+
+	Ok(LexSpec { conditions, rules, eof_rules, user_code })
 }
 
 #[test]
 fn test_expand_def(){
 	let rules: HashMap<String, String> = build_rules("path");
 
-	let formatted1 = expand_definitions("{digit}+ and {lower}.*", rules.clone());
-	let formatted2 = expand_definitions("{word}.* or {alnum}", rules.clone());
-	let formatted3 = expand_definitions("{ascii} and {blank}.*", rules.clone());
+	let formatted1 = expand_definitions("{digit}+ and {lower}.*", &rules).unwrap();
+	let formatted2 = expand_definitions("{word}.* or {alnum}", &rules).unwrap();
+	let formatted3 = expand_definitions("{ascii} and {blank}.*", &rules).unwrap();
 	
 	assert_eq!(formatted1, "[0-9]+ and [a-z].*");
 	assert_eq!(formatted2, "[0-9A-Za-z_].* or [0-9A-Za-z]");
     assert_eq!(formatted3, "[\\x00-\\x7F] and [\\t ].*");
-}
\ No newline at end of file
+    assert_eq!(expand_definitions("{nothing}", &rules), Err("nothing".to_string()));
+}
+
+#[test]
+fn test_parse_spec() {
+	let spec = parse_spec(r#"
+%x STR
+ab  a(?:b)
+%%
+{ab}+       { return Some(Token::Ab); }
+<STR>a      begin(INITIAL);
+<INITIAL,STR>\.   {
+    return Some(Token::Dot);
+}
+%%
+enum Token { Ab, Dot }
+"#).unwrap();
+
+	assert_eq!(spec.conditions.len(), 2);
+	assert!(spec.conditions[1].exclusive);
+	assert_eq!(spec.rules.len(), 3);
+	assert_eq!(spec.rules[0].pattern, "{ab}+");
+	assert_eq!(spec.rules[0].action, "{ return Some(Token::Ab); }");
+	assert_eq!(spec.rules[1].action, "begin(INITIAL);");
+	assert_eq!(spec.rules[2].conditions, vec![0, 1]);
+	assert_eq!(spec.rules[2].action, "{\n    return Some(Token::Dot);\n}");
+	assert_eq!(spec.active_rules(0), vec![0, 2]);
+	assert_eq!(spec.active_rules(1), vec![1, 2]);
+	assert_eq!(spec.user_code, "enum Token { Ab, Dot }\n");
+}
+
+#[test]
+fn test_parse_eof_rules() {
+	let spec = parse_spec(r#"
+%x STR COMMENT
+%%
+a                 return Some(1);
+<<EOF>>           return Some(0);
+<STR><<EOF>>      panic!("unterminated string");
+"#).unwrap();
+
+	assert_eq!(spec.rules.len(), 1);
+	assert_eq!(spec.eof_rules.len(), 2);
+	assert_eq!(spec.eof_action(0), Some("return Some(0);"));
+	assert_eq!(spec.eof_action(1), Some("panic!(\"unterminated string\");"));
+	assert_eq!(spec.eof_action(2), Some("return Some(0);"));
+
+	let dup = parse_spec("%%\n<<EOF>> a\n<*><<EOF>> b\n<<EOF>> c\n");
+	assert_eq!(dup.err().unwrap().line, 4);
+	let undeclared = parse_spec("%%\n<STR><<EOF>> a\n");
+	assert_eq!(undeclared.err().unwrap().message, "undeclared start condition 'STR'");
+}
+
+#[test]
+fn test_unsupported_patterns() {
+	let err = parse_spec("%%\na  return 1;\n^b  return 2;\n").err().unwrap();
+	assert_eq!(err.line, 3);
+	assert_eq!(err.message, "invalid pattern '^b': anchors are not supported");
+	assert_eq!(parse_spec("%option set-operators\n%%\nx&\\bz  return 1;\n").err().unwrap().line, 3);
+	assert!(parse_spec("%%\n(?i)ab  return 1;\n").is_ok());
+}
+
+#[test]
+fn test_set_operators_option() {
+	// without the option, & and ~ are literal characters
+	let spec = parse_spec("%%\na&~b  return 1;\n").unwrap();
+	assert!(spec.condition_nfa(0).simulate("a&~b"));
+
+	let spec = parse_spec("%option set-operators\n%%\n[a-z]+&~b  return 1;\na\\&  return 2;\n").unwrap();
+	let nfa = spec.condition_nfa(0);
+	assert!(nfa.simulate("ab") && !nfa.simulate("b"));
+	assert_eq!(nfa.longest_match("a&"), Some((1, 2)));
+
+	let err = parse_spec("%option set-operators\n%%\na&  return 1;\n").err().unwrap();
+	assert_eq!(err.message, "invalid pattern 'a&': missing operand at position 2; a literal & or ~ is written \\& or \\~");
+	assert_eq!(parse_spec("%option yywrap\n%%\n").err().unwrap().message, "unknown option 'yywrap'");
+}
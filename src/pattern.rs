@@ -0,0 +1,530 @@
+//
+// pattern.rs
+// Rule patterns with intersection and complement
+//
+// A pattern is a regular expression in the syntax of regex_syntax, where
+// `A&B` also matches the strings matched by both A and B, and `~A` the
+// strings not matched by A. `&` binds tighter than `|` and looser than
+// concatenation, and `~` applies to the atom after it with its
+// repetitions, so `~a*` is `~(a*)`. The operators and the groups around
+// them are parsed here, and the pieces of regular expression between
+// them by regex_syntax; a literal `&` or `~` is written `\&` or `\~`.
+// The operators are only enabled by `%option set-operators` in a spec.
+//
+
+use regex_syntax::Expr;
+use regex_syntax::Repeater;
+
+use arena::SpecArena;
+use arena::SpecID;
+use nfa::Spec;
+use rules;
+
+/// A parsed rule pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Regex(Expr),
+    Concat(Vec<Pattern>),
+    Alternate(Vec<Pattern>),
+    Intersection(Vec<Pattern>),
+    Complement(Box<Pattern>),
+    Repeat(Box<Pattern>, Repeater)
+}
+
+impl Pattern {
+    /// Converts the pattern into a `Spec`, the tree of the nodes
+    /// `to_spec_id` adds to an arena.
+    pub fn to_spec(&self) -> Spec {
+        let mut arena = SpecArena::new();
+        let id = self.to_spec_id(&mut arena);
+        arena.to_spec(id)
+    }
+
+    /// Adds the pattern to `arena`, like `rules::regex_to_spec_id`.
+    pub fn to_spec_id(&self, arena: &mut SpecArena) -> SpecID {
+        match *self {
+            Pattern::Regex(ref e) => rules::regex_to_spec_id(arena, e),
+            Pattern::Concat(ref ps) | Pattern::Alternate(ref ps) | Pattern::Intersection(ref ps) => {
+                let mut s = ps[0].to_spec_id(arena);
+                for p in ps.iter().skip(1) {
+                    let next = p.to_spec_id(arena);
+                    s = match *self {
+                        Pattern::Concat(_) => arena.concat(s, next),
+                        Pattern::Alternate(_) => arena.union(s, next),
+                        _ => arena.intersection(s, next)
+                    };
+                }
+                s
+            },
+            Pattern::Complement(ref p) => {
+                let s = p.to_spec_id(arena);
+                arena.complement(s)
+            },
+            Pattern::Repeat(ref p, r) => {
+                let s = p.to_spec_id(arena);
+                rules::repeat_spec_id(arena, s, r)
+            }
+        }
+    }
+
+    /// Returns the index and name of each named group, in order.
+    pub fn group_names(&self) -> Vec<(usize, String)> {
+        match *self {
+            Pattern::Regex(ref e) => rules::group_names(e),
+            Pattern::Concat(ref ps) | Pattern::Alternate(ref ps) | Pattern::Intersection(ref ps) =>
+                ps.iter().flat_map(|p| p.group_names().into_iter()).collect(),
+            Pattern::Complement(ref p) | Pattern::Repeat(ref p, _) => p.group_names()
+        }
+    }
+}
+
+/// Parses a rule pattern.
+pub fn parse(text: &str) -> Result<Pattern, String> {
+    let chars: Vec<char> = text.chars().collect();
+    if !has_operators(&chars) && !text.contains("\\&") && !text.contains("\\~") {
+        let e = Expr::parse(text).map_err(|e| e.to_string())?;
+        rules::check_regex(&e)?;
+        return Ok(Pattern::Regex(e));
+    }
+
+    let mut parser = Parser { chars, pos: 0, group: 1 };
+    let p = parser.alternate().map_err(|e| {
+        if e.starts_with("missing operand") {
+            format!("{}; a literal & or ~ is written \\& or \\~", e)
+        } else {
+            e
+        }
+    })?;
+    if parser.pos < parser.chars.len() {
+        return Err(format!("unopened group at position {}", parser.pos));
+    }
+    Ok(p)
+}
+
+/// Parses a rule pattern where `&` and `~` are literal characters, as
+/// in the regular expressions of regex_syntax, for the specs that do not
+/// enable the operators. `\&` and `\~` are accepted too.
+pub fn parse_without_operators(text: &str) -> Result<Pattern, String> {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(d) if d == '&' || d == '~' => unescaped.push(d),
+                Some(d) => unescaped.extend(['\\', d].iter()),
+                None => unescaped.push(c)
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    let e = Expr::parse(&unescaped).map_err(|e| e.to_string())?;
+    rules::check_regex(&e)?;
+    Ok(Pattern::Regex(e))
+}
+
+// index after the class that starts at i
+fn class_end(chars: &[char], mut i: usize) -> usize {
+    i += 1;
+    if chars.get(i) == Some(&'^') {
+        i += 1;
+    }
+    // a ] right after the opening is a member of the class
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '[' if chars.get(i + 1) == Some(&':') => {
+                // a named class like [:alpha:]
+                i += 2;
+                while i < chars.len() && !(chars[i] == ':' && chars.get(i + 1) == Some(&']')) {
+                    i += 1;
+                }
+                i += 2;
+            },
+            ']' => return i + 1,
+            _ => i += 1
+        }
+    }
+    chars.len()
+}
+
+// whether chars have & or ~ outside of classes and escapes
+fn has_operators(chars: &[char]) -> bool {
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '[' => i = class_end(chars, i),
+            '&' | '~' => return true,
+            _ => i += 1
+        }
+    }
+    false
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    // index of the next capturing group
+    group: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn alternate(&mut self) -> Result<Pattern, String> {
+        let mut ps = vec![self.intersection()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            ps.push(self.intersection()?);
+        }
+        Ok(if ps.len() == 1 { ps.pop().unwrap() } else { Pattern::Alternate(ps) })
+    }
+
+    fn intersection(&mut self) -> Result<Pattern, String> {
+        let mut ps = vec![self.concat()?];
+        while self.peek() == Some('&') {
+            self.pos += 1;
+            ps.push(self.concat()?);
+        }
+        if ps.len() == 1 {
+            return Ok(ps.pop().unwrap());
+        }
+        for p in ps.iter() {
+            check_operand(p)?;
+        }
+        Ok(Pattern::Intersection(ps))
+    }
+
+    // consecutive atoms without operators are kept as text, to be parsed
+    // by regex_syntax together
+    fn concat(&mut self) -> Result<Pattern, String> {
+        let mut ps = Vec::new();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None | Some('|') | Some('&') | Some(')') => break,
+                Some('~') => {
+                    self.flush(&mut text, &mut ps)?;
+                    ps.push(self.unary()?);
+                },
+                Some('(') if self.operator_group()? => {
+                    self.flush(&mut text, &mut ps)?;
+                    ps.push(self.unary()?);
+                },
+                Some(_) => {
+                    let start = self.pos;
+                    self.atom()?;
+                    self.pos = self.repeats_end(self.pos);
+                    self.copy(start, self.pos, &mut text);
+                }
+            }
+        }
+        self.flush(&mut text, &mut ps)?;
+
+        match ps.len() {
+            0 => Err(format!("missing operand at position {}", self.pos)),
+            1 => Ok(ps.pop().unwrap()),
+            _ => Ok(Pattern::Concat(ps))
+        }
+    }
+
+    // an atom with its repetitions, or the complement of one
+    fn unary(&mut self) -> Result<Pattern, String> {
+        match self.peek() {
+            None | Some('|') | Some('&') | Some(')') => Err(format!("missing operand at position {}", self.pos)),
+            Some('~') => {
+                self.pos += 1;
+                let p = self.unary()?;
+                check_operand(&p)?;
+                Ok(Pattern::Complement(Box::new(p)))
+            },
+            Some('(') if self.operator_group()? => {
+                let end = self.group_end(self.pos)?;
+                let mut start = self.pos + 1;
+                if self.chars[start..end].starts_with(&['?', ':']) {
+                    start += 2;
+                } else if self.chars.get(start) == Some(&'?') {
+                    return Err(format!("a group with & or ~ cannot be named or have flags, at position {}", self.pos));
+                }
+                let mut inner = Parser { chars: self.chars[start..end].to_vec(), pos: 0, group: self.group };
+                let p = inner.alternate()?;
+                self.group = inner.group;
+                self.pos = end + 1;
+                self.repeats(p)
+            },
+            Some(_) => {
+                let start = self.pos;
+                self.atom()?;
+                self.pos = self.repeats_end(self.pos);
+                let mut text = String::new();
+                self.copy(start, self.pos, &mut text);
+                self.regex(&text)
+            }
+        }
+    }
+
+    // whether the group at the current position has operators inside
+    fn operator_group(&self) -> Result<bool, String> {
+        let end = self.group_end(self.pos)?;
+        Ok(has_operators(&self.chars[self.pos + 1..end]))
+    }
+
+    // index of the ) closing the group that starts at i
+    fn group_end(&self, i: usize) -> Result<usize, String> {
+        let mut depth = 0;
+        let mut j = i;
+        while j < self.chars.len() {
+            match self.chars[j] {
+                '\\' => j += 2,
+                '[' => j = class_end(&self.chars, j),
+                '(' => {
+                    depth += 1;
+                    j += 1;
+                },
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(j);
+                    }
+                    j += 1;
+                },
+                _ => j += 1
+            }
+        }
+        Err(format!("unclosed group at position {}", i))
+    }
+
+    // moves past the atom at the current position, without repetitions
+    fn atom(&mut self) -> Result<(), String> {
+        self.pos = match self.chars[self.pos] {
+            '[' => class_end(&self.chars, self.pos),
+            '(' => self.group_end(self.pos)? + 1,
+            '\\' => {
+                let mut end = self.pos + 2;
+                match self.chars.get(self.pos + 1) {
+                    Some(&'x') | Some(&'p') | Some(&'P') if self.chars.get(end) == Some(&'{') => {
+                        while end < self.chars.len() && self.chars[end] != '}' {
+                            end += 1;
+                        }
+                        end += 1;
+                    },
+                    Some(&'x') => end += 2,
+                    Some(&'p') | Some(&'P') => end += 1,
+                    _ => ()
+                }
+                end
+            },
+            _ => self.pos + 1
+        };
+        self.pos = ::std::cmp::min(self.pos, self.chars.len());
+        Ok(())
+    }
+
+    // index after the repetition operators that start at i
+    fn repeats_end(&self, mut i: usize) -> usize {
+        loop {
+            match self.chars.get(i) {
+                Some(&'*') | Some(&'+') | Some(&'?') => i += 1,
+                Some(&'{') => match self.chars[i..].iter().position(|&c| c == '}') {
+                    Some(n) => i += n + 1,
+                    None => return i
+                },
+                _ => return i
+            }
+        }
+    }
+
+    // applies the repetition operators at the current position to p
+    fn repeats(&mut self, mut p: Pattern) -> Result<Pattern, String> {
+        let end = self.repeats_end(self.pos);
+        let mut lazy_allowed = false;
+        while self.pos < end {
+            let r = match self.chars[self.pos] {
+                // a ? after a repetition makes it lazy, which makes no
+                // difference for the longest match
+                '?' if lazy_allowed => {
+                    self.pos += 1;
+                    lazy_allowed = false;
+                    continue;
+                },
+                '*' => Repeater::ZeroOrMore,
+                '+' => Repeater::OneOrMore,
+                '?' => Repeater::ZeroOrOne,
+                _ => {
+                    let close = self.pos + self.chars[self.pos..].iter().position(|&c| c == '}').unwrap();
+                    let counts: String = self.chars[self.pos + 1..close].iter().cloned().collect();
+                    let r = parse_range(&counts).ok_or(format!("invalid repetition {{{}}}", counts))?;
+                    self.pos = close;
+                    r
+                }
+            };
+            self.pos += 1;
+            lazy_allowed = true;
+            p = Pattern::Repeat(Box::new(p), r);
+        }
+        Ok(p)
+    }
+
+    // copies chars[start..end] to text, with the escaped operators in a
+    // form that regex_syntax accepts
+    fn copy(&self, start: usize, end: usize, text: &mut String) {
+        let operator = |i: usize| i < end && (self.chars[i] == '&' || self.chars[i] == '~');
+        let mut i = start;
+        while i < end {
+            match self.chars[i] {
+                '[' => {
+                    // inside a class, the operators need no escape
+                    let close = ::std::cmp::min(class_end(&self.chars, i), end);
+                    while i < close {
+                        if self.chars[i] == '\\' && i + 1 < close {
+                            if !operator(i + 1) {
+                                text.push('\\');
+                            }
+                            i += 1;
+                        }
+                        text.push(self.chars[i]);
+                        i += 1;
+                    }
+                },
+                '\\' if operator(i + 1) => {
+                    text.push('[');
+                    text.push(self.chars[i + 1]);
+                    text.push(']');
+                    i += 2;
+                },
+                '\\' if i + 1 < end => {
+                    text.push('\\');
+                    text.push(self.chars[i + 1]);
+                    i += 2;
+                },
+                c => {
+                    text.push(c);
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self, text: &mut String, ps: &mut Vec<Pattern>) -> Result<(), String> {
+        if !text.is_empty() {
+            ps.push(self.regex(text)?);
+            text.clear();
+        }
+        Ok(())
+    }
+
+    // parses a piece of the pattern with regex_syntax, numbering its
+    // groups after the ones of the previous pieces
+    fn regex(&mut self, text: &str) -> Result<Pattern, String> {
+        let mut e = Expr::parse(text).map_err(|e| e.to_string())?;
+        rules::check_regex(&e)?;
+        renumber(&mut e, &mut self.group);
+        Ok(Pattern::Regex(e))
+    }
+}
+
+// the operands of & and ~ are built as DFAs, which do not record where
+// groups match
+fn check_operand(p: &Pattern) -> Result<(), String> {
+    match p.group_names().first() {
+        Some((_, name)) => Err(format!("named group {} cannot be inside an operand of & or ~", name)),
+        None => Ok(())
+    }
+}
+
+fn parse_range(counts: &str) -> Option<Repeater> {
+    let mut parts = counts.splitn(2, ',');
+    let min = parts.next().unwrap().trim().parse().ok()?;
+    let max = match parts.next() {
+        None => Some(min),
+        Some(m) if m.trim().is_empty() => None,
+        Some(m) => Some(m.trim().parse().ok()?)
+    };
+    Some(Repeater::Range { min, max })
+}
+
+fn renumber(e: &mut Expr, next: &mut usize) {
+    match *e {
+        Expr::Group { ref mut e, ref mut i, .. } => {
+            if i.is_some() {
+                *i = Some(*next);
+                *next += 1;
+            }
+            renumber(e, next);
+        },
+        Expr::Repeat { ref mut e, .. } => renumber(e, next),
+        Expr::Concat(ref mut es) | Expr::Alternate(ref mut es) => for e in es.iter_mut() { renumber(e, next) },
+        _ => ()
+    }
+}
+
+#[test]
+fn test_parse_pattern() {
+    let re = |p| Pattern::Regex(Expr::parse(p).unwrap());
+
+    assert_eq!(parse(r"[a-z]+"), Ok(re(r"[a-z]+")));
+    assert_eq!(parse(r"[a-z]+&~(if|else)"),
+               Ok(Pattern::Intersection(vec![re(r"[a-z]+"), Pattern::Complement(Box::new(re(r"(if|else)")))])));
+    assert_eq!(parse(r"a|b&c"), Ok(Pattern::Alternate(vec![re("a"), Pattern::Intersection(vec![re("b"), re("c")])])));
+    assert_eq!(parse(r"x~a*y"), Ok(Pattern::Concat(vec![re("x"), Pattern::Complement(Box::new(re("a*"))), re("y")])));
+    assert_eq!(parse(r"(a&b)+"),
+               Ok(Pattern::Repeat(Box::new(Pattern::Intersection(vec![re("a"), re("b")])), Repeater::OneOrMore)));
+    assert_eq!(parse(r"\&[\~&]~x"), Ok(Pattern::Concat(vec![re(r"[&][~&]"), Pattern::Complement(Box::new(re("x")))])));
+    assert_eq!(parse(r"\&"), Ok(re("[&]")));
+
+    // groups are numbered across the pieces
+    let p = parse(r"(?P<a>x)~(y)(?P<b>z)").unwrap();
+    assert_eq!(p.group_names(), vec![(1, "a".to_string()), (3, "b".to_string())]);
+
+    assert_eq!(parse(r"a&"), Err(r"missing operand at position 2; a literal & or ~ is written \& or \~".to_string()));
+    assert!(parse(r"~").is_err());
+    assert!(parse(r"(?P<n>a&b)").is_err());
+    assert_eq!(parse(r"(?P<n>a)b&c"), Err("named group n cannot be inside an operand of & or ~".to_string()));
+    assert!(parse(r"x~(?P<n>a)").is_err());
+    assert!(parse(r"(?P<n>x)~(a)").is_ok());
+    assert!(parse(r"(a&b").is_err());
+    assert!(parse(r"a&b)").is_err());
+    assert!(parse(r"a&[b").is_err());
+}
+
+#[test]
+fn test_parse_without_operators() {
+    let re = |p| Pattern::Regex(Expr::parse(p).unwrap());
+
+    assert_eq!(parse_without_operators(r"[a-z]+"), Ok(re(r"[a-z]+")));
+    assert_eq!(parse_without_operators(r"a&b|~"), Ok(re(r"a&b|~")));
+    assert_eq!(parse_without_operators(r"\&[\&~\]]+\\~*"), Ok(re(r"&[&~\]]+\\~*")));
+    assert!(parse_without_operators(r"^a&").is_err());
+}
+
+#[test]
+fn test_pattern_automata() {
+    use arena::SpecArena;
+    use nfa::Construction;
+    use nfa::NFABuilder;
+    use utf8;
+
+    let patterns = [r"/\*~(.*\*/.*)\*/", r"[a-z]+&~(if|else)", r"if", r"(a|b)+&~(.*bb.*)&(..)*"];
+    let mut arena = SpecArena::new();
+    let ids: Vec<SpecID> = patterns.iter().map(|p| parse(p).unwrap().to_spec_id(&mut arena)).collect();
+    let specs: Vec<Spec> = patterns.iter().map(|p| parse(p).unwrap().to_spec()).collect();
+    let nfa = NFABuilder::build_from_arena(&arena, &ids);
+    let glushkov = NFABuilder::build_from_specs_with(&mut specs.clone(), Construction::Glushkov);
+    let bytes = NFABuilder::build_from_specs(&mut specs.clone().into_iter().map(utf8::utf8_spec).collect());
+
+    let cases = [("/* a */", Some((0, 7))), ("/* a */ b */", Some((0, 7))), ("/**/", Some((0, 4))),
+                 ("/* é", None), ("if", Some((2, 2))), ("iff", Some((1, 3))), ("else", Some((1, 3))), ("elsex", Some((1, 5))),
+                 ("abab", Some((1, 4))), ("abba", Some((1, 4))), ("aba", Some((1, 3))), ("", None)];
+    for &(w, expected) in cases.iter() {
+        assert_eq!(nfa.longest_match(w), expected, "{}", w);
+        assert_eq!(glushkov.longest_match(w), expected, "{}", w);
+        let expected_bytes = expected.map(|(r, n)| (r, utf8::byte_string(&w.as_bytes()[..n]).chars().count()));
+        assert_eq!(bytes.longest_match(&utf8::byte_string(w.as_bytes())), expected_bytes, "{}", w);
+    }
+}
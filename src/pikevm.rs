@@ -0,0 +1,193 @@
+//
+// pikevm.rs
+// NFA simulation with capture positions (Pike VM)
+//
+// The simulation keeps one thread for each NFA state in the current set,
+// with the positions saved by the group states on the way to it. Threads
+// are kept in order of priority (the order of the transitions followed),
+// and a state reached by several threads keeps the captures of the first.
+//
+
+use nfa::Label;
+use nfa::NFA;
+use nfa::RuleID;
+use nfa::StateID;
+use stateset::StateSet;
+
+/// The groups captured by the longest match of a rule.
+#[derive(Debug, PartialEq)]
+pub struct Captures {
+    pub rule: RuleID,
+    // start and end of each group, with group 0 the whole match
+    groups: Vec<Option<(usize, usize)>>
+}
+
+impl Captures {
+    /// Returns the number of groups, including group 0.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Returns the span of group `i` in bytes, or `None` if it did not
+    /// take part in the match.
+    pub fn get(&self, i: usize) -> Option<(usize, usize)> {
+        self.groups.get(i).cloned().unwrap_or(None)
+    }
+
+    /// Builds the captures of a match of `rule` ending at `end`, from the
+    /// positions saved in the slots of its groups.
+    pub fn from_slots(rule: RuleID, slots: &[Option<usize>], end: usize) -> Captures {
+        let mut groups: Vec<Option<(usize, usize)>> = slots.chunks(2).map(|g| match (g[0], g[1]) {
+            (Some(start), Some(end)) => Some((start, end)),
+            _ => None
+        }).collect();
+        groups[0] = Some((0, end));
+        Captures { rule, groups }
+    }
+}
+
+/// Returns the number of capture slots used by the group states of
+/// `nfa`, two for each group, including group 0.
+pub fn num_slots(nfa: &NFA) -> usize {
+    let max_slot = (0..nfa.num_states()).filter_map(|s| nfa.get_state(s).unwrap().save()).max();
+    // slots 0 and 1 are for group 0
+    max_slot.map_or(2, |m| (m | 1) + 1)
+}
+
+struct Thread {
+    state: StateID,
+    slots: Vec<Option<usize>>
+}
+
+/// A matcher that records the capture groups of the rules of an NFA
+/// built by Thompson's construction.
+pub struct PikeVM<'a> {
+    nfa: &'a NFA,
+    nslots: usize
+}
+
+impl<'a> PikeVM<'a> {
+    pub fn new(nfa: &'a NFA) -> PikeVM<'a> {
+        PikeVM { nfa, nslots: num_slots(nfa) }
+    }
+
+    // adds the threads for s and the states reached from it by epsilon
+    // transitions, in order of priority
+    fn add_thread(&self, list: &mut Vec<Thread>, seen: &mut StateSet, s: StateID, slots: Vec<Option<usize>>, pos: usize) {
+        let mut stack = vec![(s, slots)];
+        while let Some((s, mut slots)) = stack.pop() {
+            if !seen.insert(s) {
+                continue;
+            }
+            let st = self.nfa.get_state(s).unwrap();
+            if let Some(slot) = st.save() {
+                slots[slot] = Some(pos);
+            }
+            // pushed in reverse, so that the first transition is followed first
+            for t in st.transitions().iter().rev() {
+                if *t.label() == Label::Epsilon {
+                    stack.push((t.target(), slots.clone()));
+                }
+            }
+            list.push(Thread { state: s, slots });
+        }
+    }
+
+    // the captures of the thread in list that recognizes the highest
+    // priority rule, if any, for a match ending at pos
+    fn accepted(&self, list: &[Thread], pos: usize) -> Option<Captures> {
+        let mut best: Option<(RuleID, &Thread)> = None;
+        for th in list.iter() {
+            if let Some(r) = self.nfa.get_state(th.state).unwrap().rule() {
+                if best.is_none_or(|(b, _)| r < b) {
+                    best = Some((r, th));
+                }
+            }
+        }
+
+        best.map(|(rule, th)| Captures::from_slots(rule, &th.slots, pos))
+    }
+
+    /// Finds the longest prefix of `input` recognized by the automaton,
+    /// like `NFA::longest_match`, with the spans of the groups of the rule
+    /// recognized.
+    pub fn captures(&self, input: &str) -> Option<Captures> {
+        let n = self.nfa.num_states();
+        let mut clist = Vec::new();
+        let mut nlist = Vec::new();
+        let mut seen = StateSet::new(n);
+        let mut targets = Vec::new();
+
+        self.add_thread(&mut clist, &mut seen, self.nfa.start_state(), vec![None; self.nslots], 0);
+        let mut last = self.accepted(&clist, 0);
+
+        for (i, c) in input.char_indices() {
+            let pos = i + c.len_utf8();
+            seen.clear();
+            for th in clist.drain(..) {
+                targets.clear();
+                self.nfa.get_state(th.state).unwrap().step_each(c, |t| targets.push(t));
+                for &t in targets.iter() {
+                    self.add_thread(&mut nlist, &mut seen, t, th.slots.clone(), pos);
+                }
+            }
+            ::std::mem::swap(&mut clist, &mut nlist);
+            if clist.is_empty() {
+                break;
+            }
+            if let Some(caps) = self.accepted(&clist, pos) {
+                last = Some(caps);
+            }
+        }
+
+        last
+    }
+
+    pub fn longest_match(&self, input: &str) -> Option<(RuleID, usize)> {
+        self.captures(input).map(|caps| (caps.rule, caps.get(0).unwrap().1))
+    }
+}
+
+#[test]
+fn test_captures() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let nfa = NFABuilder::build_from_specs(&mut vec![spec(r"([0-9]+)(\.([0-9]+))?(e([0-9]+))?"),
+                                                     spec(r#""([^"]*)""#),
+                                                     spec(r"[a-z]+")]);
+    let vm = PikeVM::new(&nfa);
+
+    let caps = vm.captures("3.14e10+x").unwrap();
+    assert_eq!(caps.rule, 0);
+    assert_eq!(caps.len(), 6);
+    assert_eq!(caps.get(0), Some((0, 7)));
+    assert_eq!(caps.get(1), Some((0, 1)));
+    assert_eq!(caps.get(3), Some((2, 4)));
+    assert_eq!(caps.get(5), Some((5, 7)));
+
+    let caps = vm.captures("42;").unwrap();
+    assert_eq!(caps.get(1), Some((0, 2)));
+    assert_eq!(caps.get(2), None);
+    assert_eq!(caps.get(3), None);
+
+    let caps = vm.captures(r#""héllo" x"#).unwrap();
+    assert_eq!(caps.rule, 1);
+    assert_eq!(caps.get(1), Some((1, 7)));
+
+    let caps = vm.captures("abc1").unwrap();
+    assert_eq!((caps.rule, caps.get(0), caps.get(1)), (2, Some((0, 3)), None));
+    assert_eq!(vm.captures("!"), None);
+
+    for w in ["1.5", "1.", "\"\"", "\"a", "if", ""].iter() {
+        assert_eq!(vm.longest_match(w), nfa.longest_match(w), "{}", w);
+    }
+
+    // the last iteration of a repeated group is captured
+    let nfa = NFABuilder::build_from_spec(spec(r"(?:([a-z])[0-9])+"));
+    let caps = PikeVM::new(&nfa).captures("a1b2c3").unwrap();
+    assert_eq!(caps.get(1), Some((4, 5)));
+}
@@ -1,125 +1,243 @@
+use regex_syntax::CharClass;
+use regex_syntax::ClassRange;
 use regex_syntax::Expr;
 use regex_syntax::Repeater;
 
-use nfa::NFA;
+use arena::SpecArena;
+use arena::SpecID;
+use arena::SpecNode;
 use nfa::Spec;
+#[cfg(test)]
 use nfa::NFABuilder;
 use nfa::Label;
+use pattern::Pattern;
+
+/// A lexer rule: a regular expression and the action to run when
+/// it matches.
+pub struct Rule {
+    pub pattern: String,
+    pub regex: Pattern,
+    pub action: String,
+    /// Start conditions in which the rule is active; if empty, the rule
+    /// is active in all inclusive start conditions.
+    pub conditions: Vec<usize>,
+}
+
+/// A rule whose action runs when the input is exhausted.
+pub struct EofRule {
+    pub action: String,
+    /// Start conditions the action applies to; if empty, it applies to
+    /// every start condition without an end-of-file rule of its own.
+    pub conditions: Vec<usize>,
+}
 
-struct Rule {
-    regex: Expr,
-    action: String,
+/// The constructions a counted repetition is made of, so that
+/// `repeat_range` can build it as a spec or directly as an automaton.
+pub trait Repetition {
+    type Item;
+    /// Returns a new copy of the repeated expression.
+    fn copy(&mut self) -> Self::Item;
+    fn epsilon(&mut self) -> Self::Item;
+    fn concat(&mut self, a: Self::Item, b: Self::Item) -> Self::Item;
+    fn union(&mut self, a: Self::Item, b: Self::Item) -> Self::Item;
+    fn star(&mut self, a: Self::Item) -> Self::Item;
 }
 
-/// Converts a sequence of characters into a concatenation of simple
-/// transitions.
+/// Builds `s{min,max}` from copies of `s`, in size linear in `max`.
 ///
-/// The return value is a Spec containing the concatenation of all
-/// single transition automata generated for each character in `chars`.
-fn literal_to_spec(chars: &Vec<char>) -> Spec {
-    // take the first
-    let mut tmp: Box<Spec> = Spec::single(Label::Symbol((*chars)[0]));
-
-    // iterate for the others
-    for i in chars.iter().skip(1) {
-        let aux = Spec::single(Label::Symbol(*i));
-        tmp = Spec::concat(tmp,aux);
+/// The optional copies after the first `min` are nested, as in
+/// `(s(s(s)?)?)?` for `s{0,3}`, so that they all share the same final
+/// state instead of being alternatives of each length.
+pub fn repeat_range<R: Repetition>(r: &mut R, min: u32, max: Option<u32>) -> R::Item {
+    let mut res: Option<R::Item> = None;
+    for _ in 0..min {
+        let copy = r.copy();
+        res = Some(match res {
+            None => copy,
+            Some(s) => r.concat(s, copy)
+        });
     }
 
-    // return the concatenation
-    *tmp
+    let tail = match max {
+        None => {
+            let copy = r.copy();
+            Some(r.star(copy))
+        },
+        Some(max) => {
+            let mut tail: Option<R::Item> = None;
+            for _ in min..max {
+                let copy = r.copy();
+                let copy = match tail {
+                    None => copy,
+                    Some(t) => r.concat(copy, t)
+                };
+                let eps = r.epsilon();
+                tail = Some(r.union(eps, copy));
+            }
+            tail
+        }
+    };
+
+    match (res, tail) {
+        (Some(s), Some(t)) => r.concat(s, t),
+        (Some(s), None) => s,
+        (None, Some(t)) => t,
+        (None, None) => r.epsilon()
+    }
 }
 
-/// Converts a concatenation of `Expr` into a concatenation of `Spec`s.
-///
-/// The return value is a `Spec` containing the concatenation of all
-/// REs in `exprs`.
-fn concat_to_spec(exprs: &Vec<Expr>) -> Spec {
-    let mut tmp: Box<Spec> = Box::new(regex_to_nfa_spec(&(exprs[0])));
-
-    for i in exprs.iter().skip(1) {
-        let aux : Box<Spec> = Box::new(regex_to_nfa_spec(&i));
-        tmp  = Spec::concat(tmp,aux);
+// copies of a spec, for repeat_range
+struct SpecCopies(Box<Spec>);
+
+impl Repetition for SpecCopies {
+    type Item = Box<Spec>;
+
+    fn copy(&mut self) -> Box<Spec> {
+        self.0.clone()
     }
 
-    *tmp
-}
+    fn epsilon(&mut self) -> Box<Spec> {
+        Spec::single(Label::Epsilon)
+    }
 
-/// Converts a union of `Expr` into a union of `Spec`s.
-///
-/// The return value is a `Spec` containing the union of all
-/// REs in `exprs`.
-fn alternate_to_spec(exprs: &Vec<Expr>) -> Spec {
-    let mut tmp: Box<Spec> = Box::new(regex_to_nfa_spec(&(exprs[0])));;
-
-    for i in exprs.iter().skip(1) {
-        let aux : Box<Spec> = Box::new(regex_to_nfa_spec(&i));
-        tmp  = Spec::union(tmp,aux);
+    fn concat(&mut self, a: Box<Spec>, b: Box<Spec>) -> Box<Spec> {
+        Spec::concat(a, b)
+    }
+
+    fn union(&mut self, a: Box<Spec>, b: Box<Spec>) -> Box<Spec> {
+        Spec::union(a, b)
     }
 
-    *tmp
+    fn star(&mut self, a: Box<Spec>) -> Box<Spec> {
+        Spec::star(a)
+    }
 }
 
-fn repeater_range(s1: Box<Spec>, min: u32, max: Option<u32>) -> Spec {
-    let mut begin:u32 = 0;
-    let mut aux:Box<Spec>;
-    let mut spec:Box<Spec>;
+/// Converts `s1{min,max}` into a `Spec` of size linear in `max`, as
+/// built by `repeat_range`.
+pub fn repeater_range(s1: Box<Spec>, min: u32, max: Option<u32>) -> Spec {
+    *repeat_range(&mut SpecCopies(s1), min, max)
+}
 
-    if min == 0 {
-        aux = Spec::single(Label::Epsilon);
-    } else {
-        aux = s1.clone();
-        begin = 1;
+/// Applies the repetition `r` to the node `s` of `arena`.
+pub fn repeat_spec_id(arena: &mut SpecArena, s: SpecID, r: Repeater) -> SpecID {
+    match r {
+        Repeater::ZeroOrOne => {
+            let eps = arena.single(Label::Epsilon);
+            arena.union(eps, s)
+        },
+        Repeater::ZeroOrMore => arena.star(s),
+        Repeater::OneOrMore => {
+            let star = arena.star(s);
+            arena.concat(s, star)
+        },
+        Repeater::Range { min, max } => arena.repeat(s, min, max)
     }
+}
 
-    for _ in begin..min {
-        aux = Spec::concat(aux, s1.clone());
+/// Checks that `re` only uses constructs that can be built into an
+/// automaton: a lexer matches at the current position of its input, so
+/// anchors and word boundaries are not supported.
+pub fn check_regex(re: &Expr) -> Result<(), String> {
+    match *re {
+        Expr::StartLine | Expr::EndLine | Expr::StartText | Expr::EndText =>
+            Err("anchors are not supported".to_string()),
+        Expr::WordBoundary | Expr::NotWordBoundary => Err("word boundaries are not supported".to_string()),
+        Expr::Group { ref e, .. } | Expr::Repeat { ref e, .. } => check_regex(e),
+        Expr::Concat(ref exprs) | Expr::Alternate(ref exprs) => exprs.iter().try_for_each(check_regex),
+        _ => Ok(())
     }
+}
 
-    if max == None {
-        spec = Spec::concat(aux, Spec::star(s1))
+// the label matching c and, if casei is set, the other cases of c
+fn literal_label(c: char, casei: bool) -> Label {
+    if !casei {
+        return Label::Symbol(c);
+    }
+    let class = CharClass::new(vec![ClassRange { start: c, end: c }]).case_fold();
+    if class.len() == 1 && class[0].start == class[0].end {
+        Label::Symbol(c)
     } else {
-        spec = aux.clone();
-
-        //iterate min + 1 ... max
-        for _ in min..max.unwrap() {
-            aux = Spec::concat(aux, s1.clone());
-            spec = Spec::union(spec, aux.clone());
-        }
+        Label::Class(class.iter().map(|r| (r.start, r.end)).collect())
     }
-    *spec
 }
 
-/// Converts `re` and repeater specification `r` into a NFA `Spec`.
+/// Adds the regexp `re` to `arena`.
 ///
-/// The repetition behavior is translated to the basic constructs
-/// of a NFA `Spec`: union, concatenation and Kleene star of NFAs,
-/// using the NFA built for `re` as one of the operands.
-fn repeater_to_spec(re: &Expr, r: &Repeater) -> Spec {
-    let s1 = Box::new(regex_to_nfa_spec(re));
-
-    match *r {
-        Repeater::ZeroOrOne => Spec::Union(Spec::single(Label::Epsilon), s1),
-        Repeater::ZeroOrMore => Spec::Star(s1),
-        Repeater::OneOrMore => Spec::Concat(s1.clone(), Spec::star(s1)),
-        Repeater::Range { min, max } => repeater_range(s1, min, max)
+/// Repeated subexpressions are stored once, and counted repetitions are
+/// kept as `SpecNode::Repeat` nodes instead of being unrolled.
+///
+/// # Panics
+///
+/// Panics if `re` does not pass `check_regex`; the patterns of a spec
+/// are checked when it is parsed.
+pub fn regex_to_spec_id(arena: &mut SpecArena, re: &Expr) -> SpecID {
+    match *re {
+        Expr::Empty => arena.single(Label::Epsilon),
+        Expr::AnyChar | Expr::AnyCharNoNL => arena.single(Label::Any),  // FIX: don't match newline
+        Expr::Repeat { ref e, r, .. } => {
+            let s = regex_to_spec_id(arena, e);
+            repeat_spec_id(arena, s, r)
+        },
+        Expr::Literal { ref chars, casei } => {
+            let mut s = arena.single(literal_label(chars[0], casei));
+            for &c in chars.iter().skip(1) {
+                let next = arena.single(literal_label(c, casei));
+                s = arena.concat(s, next);
+            }
+            s
+        },
+        Expr::Concat(ref exprs) | Expr::Alternate(ref exprs) => {
+            let mut s = regex_to_spec_id(arena, &exprs[0]);
+            for e in exprs.iter().skip(1) {
+                let next = regex_to_spec_id(arena, e);
+                s = match *re {
+                    Expr::Concat(_) => arena.concat(s, next),
+                    _ => arena.union(s, next)
+                };
+            }
+            s
+        },
+        Expr::Group { ref e, i: Some(i), .. } => {
+            let s = regex_to_spec_id(arena, e);
+            arena.intern(SpecNode::Group(i, s))
+        },
+        Expr::Group { ref e, .. } => regex_to_spec_id(arena, e),
+        Expr::Class(ref ranges) => arena.single(Label::Class(ranges.iter().map(|r| (r.start, r.end)).collect())),
+        _ => panic!("unsupported expression {:?}", re)
     }
 }
 
-/// Converts the regexp `re` into a NFA `Spec`.
+/// Converts the regexp `re` into a NFA `Spec`, the tree of the nodes
+/// `regex_to_spec_id` adds to an arena.
 ///
-/// The function traverses the `re` syntax tree recursively, building
-/// NFA Specs for each component and combining them according to the
-/// operations in the ER.
+/// # Panics
+///
+/// Panics if `re` does not pass `check_regex`.
 pub fn regex_to_nfa_spec(re: &Expr) -> Spec {
+    let mut arena = SpecArena::new();
+    let id = regex_to_spec_id(&mut arena, re);
+    arena.to_spec(id)
+}
+
+/// Returns the index and name of each named group of `re`, in order.
+pub fn group_names(re: &Expr) -> Vec<(usize, String)> {
+    let mut names = Vec::new();
+    collect_group_names(re, &mut names);
+    names
+}
+
+fn collect_group_names(re: &Expr, names: &mut Vec<(usize, String)>) {
     match *re {
-        Expr::AnyChar => Spec::Single(Label::Any),
-        Expr::AnyCharNoNL => Spec::Single(Label::Any),  // FIX: don't match newline
-        Expr::Repeat { ref e, r, greedy } => repeater_to_spec(&*e, &r),
-        Expr::Literal{ ref chars, casei } => literal_to_spec(chars),
-        Expr::Concat(ref exprs) => concat_to_spec(&exprs),
-        Expr::Alternate(ref exprs) => alternate_to_spec(&exprs),
-        _ => unimplemented!()
+        Expr::Group { ref e, i, ref name } => {
+            if let (Some(i), Some(name)) = (i, name) {
+                names.push((i, name.clone()));
+            }
+            collect_group_names(e, names);
+        },
+        Expr::Repeat { ref e, .. } => collect_group_names(e, names),
+        Expr::Concat(ref exprs) | Expr::Alternate(ref exprs) => for e in exprs.iter() { collect_group_names(e, names) },
+        _ => ()
     }
 }
 
@@ -143,6 +261,7 @@ fn test_literal() {
 }
 
 #[test]
+#[allow(clippy::vec_init_then_push)]
 fn test_concat(){
     let re1 = Expr::parse(r"ab").unwrap();
     let re2 = Expr::parse(r"cd").unwrap();
@@ -153,7 +272,7 @@ fn test_concat(){
     tmp.push(re1);
     tmp.push(re2);
 
-    let aux = concat_to_spec(&tmp);
+    let aux = regex_to_nfa_spec(&Expr::Concat(tmp));
 
     let aux2 = NFABuilder::build_from_spec(aux);
 
@@ -164,6 +283,7 @@ fn test_concat(){
 }
 
 #[test]
+#[allow(clippy::vec_init_then_push)]
 fn test_altenate(){
     let re1 = Expr::parse(r"ab").unwrap();
     let re2 = Expr::parse(r"cd").unwrap();
@@ -173,7 +293,7 @@ fn test_altenate(){
 
     tmp.push(re1);
     tmp.push(re2);
-    let aux = alternate_to_spec(&tmp);
+    let aux = regex_to_nfa_spec(&Expr::Alternate(tmp));
 
     let aux2 = NFABuilder::build_from_spec(aux);
 
@@ -193,3 +313,63 @@ fn test_repeater_range() {
     assert!(n.simulate(""));
     assert!(!n.simulate("aaaaaaaaaaaa"));
 }
+
+#[test]
+fn test_unsupported() {
+    assert_eq!(check_regex(&Expr::parse(r"^a").unwrap()), Err("anchors are not supported".to_string()));
+    assert!(check_regex(&Expr::parse(r"(a|b$)+").unwrap()).is_err());
+    assert!(check_regex(&Expr::parse(r"\bx").unwrap()).is_err());
+    assert_eq!(check_regex(&Expr::parse(r"a+(bc)?").unwrap()), Ok(()));
+}
+
+#[test]
+fn test_class() {
+    let re = Expr::parse(r"[a-cx]").unwrap();
+    let spec = regex_to_nfa_spec(&re);
+    assert_eq!(spec, Spec::Single(Label::Class(vec![('a', 'c'), ('x', 'x')])));
+
+    let n = NFABuilder::build_from_spec(regex_to_nfa_spec(&Expr::parse(r"[^a-y]+").unwrap()));
+    assert!(n.simulate("z"));
+    assert!(n.simulate("Z\u{10FFFF}"));
+    assert!(!n.simulate("za"));
+}
+
+#[test]
+fn test_case_insensitive() {
+    let n = NFABuilder::build_from_spec(regex_to_nfa_spec(&Expr::parse(r"(?i)ab1").unwrap()));
+    for w in ["ab1", "AB1", "aB1"].iter() {
+        assert!(n.simulate(w), "{}", w);
+    }
+    assert!(!n.simulate("ab!"));
+    assert_eq!(regex_to_nfa_spec(&Expr::parse(r"(?i)k").unwrap()),
+               Spec::Single(Label::Class(vec![('K', 'K'), ('k', 'k'), ('\u{212A}', '\u{212A}')])));
+
+    let n = NFABuilder::build_from_spec(regex_to_nfa_spec(&Expr::parse(r"a(?i:b)c").unwrap()));
+    assert!(n.simulate("aBc"));
+    assert!(!n.simulate("Abc"));
+}
+
+#[test]
+fn test_repeater_range_size() {
+    let cases = [(r"\d{1,100}", "7", 1, Some(100)), (r"[a-z]{3,64}", "x", 3, Some(64)),
+                 (r"(ab){2,}", "ab", 2, None), (r"a{0}", "a", 0, Some(0))];
+
+    for &(p, unit, min, max) in cases.iter() {
+        let n = NFABuilder::build_from_spec(regex_to_nfa_spec(&Expr::parse(p).unwrap()));
+        // a few states for each copy of the repeated expression
+        assert!(n.num_states() <= 6 * 100, "{}: {} states", p, n.num_states());
+
+        for count in 0..max.unwrap_or(10) + 3 {
+            let w = unit.repeat(count);
+            let accepted = count >= min && count <= max.unwrap_or(usize::MAX);
+            assert_eq!(n.simulate(&w), accepted, "{} on {:?}", p, w);
+        }
+    }
+}
+
+#[test]
+fn test_group_names() {
+    let re = Expr::parse(r"(?P<int>[0-9]+)(\.(?P<frac>[0-9]+))?|(?:x(?P<hex>[0-9a-f]+))+").unwrap();
+    assert_eq!(group_names(&re), vec![(1, "int".to_string()), (3, "frac".to_string()), (4, "hex".to_string())]);
+    assert!(group_names(&Expr::parse(r"(a)(b)").unwrap()).is_empty());
+}
@@ -0,0 +1,517 @@
+//
+// sample.rs
+// Strings accepted by automata, drawn at random or enumerated
+//
+// Random strings are drawn from the DFA of the automaton. The number of
+// paths of each length from each state to an accepting state is counted
+// first, so that a length can be chosen among the ones with accepted
+// strings, and the path of that length can be chosen uniformly, one class
+// of input at a time. The characters are then chosen uniformly in each
+// class.
+//
+
+use classes::CharClasses;
+use nfa::DfaLimit;
+use nfa::NFA;
+use nfa::RuleID;
+use nfa::StateExplosion;
+use nfa::StateID;
+
+/// A seedable pseudo-random number generator, the 64-bit linear
+/// congruential generator also used by the tests; good enough for test
+/// inputs, not for anything else.
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.state
+    }
+
+    /// Returns a number in `[0, 1)`, from the high bits of the state,
+    /// which are the most random.
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a number in `0..n`, where `n` is positive and at most
+    /// 2^32.
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() >> 32) * n) >> 32
+    }
+
+    // an index of weights chosen with a probability proportional to its
+    // weight, or None if all are zero
+    fn choose(&mut self, weights: &[f64]) -> Option<usize> {
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut x = self.unit() * total;
+        let mut last = None;
+        for (i, &w) in weights.iter().enumerate() {
+            if w <= 0.0 {
+                continue;
+            }
+            if x < w {
+                return Some(i);
+            }
+            x -= w;
+            last = Some(i);
+        }
+        // only reached by rounding
+        last
+    }
+}
+
+/// The distribution of the lengths of the strings generated, in
+/// characters.
+#[derive(Debug, Clone)]
+pub enum Lengths {
+    /// All lengths from the first bound to the second, inclusive, with
+    /// the same probability.
+    Uniform(usize, usize),
+    /// The length `n` with a probability proportional to the weight at
+    /// index `n`.
+    Weights(Vec<f64>)
+}
+
+impl Lengths {
+    fn weights(&self) -> Vec<f64> {
+        match *self {
+            Lengths::Uniform(min, max) => (0..max + 1).map(|n| if n >= min { 1.0 } else { 0.0 }).collect(),
+            Lengths::Weights(ref ws) => ws.clone()
+        }
+    }
+}
+
+// the target of the transition of each state of dfa on each class, if any
+fn class_table(dfa: &NFA, classes: &CharClasses) -> Vec<Vec<Option<StateID>>> {
+    (0..dfa.num_states()).map(|s| {
+        (0..classes.len()).map(|k| classes.representative(k).and_then(|c| dfa.step_deterministic(s, c))).collect()
+    }).collect()
+}
+
+// whether each state of dfa accepts, or recognizes rule if given
+fn accepting(dfa: &NFA, rule: Option<RuleID>) -> Vec<bool> {
+    (0..dfa.num_states()).map(|s| {
+        let r = dfa.get_state(s).unwrap().rule();
+        match rule {
+            Some(rule) => r == Some(rule),
+            None => r.is_some()
+        }
+    }).collect()
+}
+
+/// A generator of random strings accepted by an automaton, or recognized
+/// by one of its rules.
+pub struct Generator {
+    classes: CharClasses,
+    // the characters of each class
+    ranges: Vec<Vec<(char, char)>>,
+    // the target of the transition of each state on each class
+    table: Vec<Vec<Option<StateID>>>,
+    start: StateID,
+    accept: Vec<bool>,
+    // counts[n][s] is proportional to the number of paths of n classes
+    // from s to an accepting state; the counts of each length are scaled
+    // by the same factor, to keep them finite
+    counts: Vec<Vec<f64>>,
+    lengths: Vec<f64>,
+    rng: Rng
+}
+
+impl Generator {
+    /// Builds a generator of the strings accepted by `nfa`, with lengths
+    /// drawn from `lengths` and random choices from the seed `seed`.
+    ///
+    /// Fails if the DFA of `nfa` exceeds `limit`.
+    pub fn new(nfa: &NFA, limit: &DfaLimit, lengths: Lengths, seed: u64) -> Result<Generator, StateExplosion> {
+        Ok(Generator::build(&nfa.to_dfa_limited(limit)?, None, lengths, seed))
+    }
+
+    /// Builds a generator of the strings for which `rule` wins in the
+    /// combined automaton `nfa`, i.e. the strings a lexer would return
+    /// as a single token of that rule.
+    pub fn for_rule(nfa: &NFA, limit: &DfaLimit, rule: RuleID, lengths: Lengths, seed: u64) -> Result<Generator, StateExplosion> {
+        Ok(Generator::build(&nfa.to_dfa_limited(limit)?, Some(rule), lengths, seed))
+    }
+
+    fn build(dfa: &NFA, rule: Option<RuleID>, lengths: Lengths, seed: u64) -> Generator {
+        let classes = dfa.char_classes();
+        let ranges: Vec<Vec<(char, char)>> = (0..classes.len()).map(|k| classes.ranges(k)).collect();
+        let table = class_table(dfa, &classes);
+        let accept = accepting(dfa, rule);
+
+        let lengths = lengths.weights();
+        let mut counts = vec![accept.iter().map(|&a| if a { 1.0 } else { 0.0 }).collect::<Vec<f64>>()];
+        for n in 1..lengths.len() {
+            let mut level: Vec<f64> = table.iter().map(|row| {
+                row.iter().filter_map(|&t| t).map(|t| counts[n - 1][t]).sum()
+            }).collect();
+            let max = level.iter().cloned().fold(0.0, f64::max);
+            if max > 0.0 {
+                for x in level.iter_mut() {
+                    *x /= max;
+                }
+            }
+            counts.push(level);
+        }
+
+        Generator { classes, ranges, table, start: dfa.start_state(), accept,
+                    counts, lengths, rng: Rng::new(seed) }
+    }
+
+    // a random character of class k, which is not empty
+    fn char_in(&mut self, k: usize) -> char {
+        let sizes: Vec<u64> = self.ranges[k].iter().map(|&(lo, hi)| {
+            // the ranges never contain surrogates
+            hi as u64 - lo as u64 + 1
+        }).collect();
+        let mut i = self.rng.below(sizes.iter().sum());
+        for (j, &size) in sizes.iter().enumerate() {
+            if i < size {
+                return ::std::char::from_u32(self.ranges[k][j].0 as u32 + i as u32).unwrap();
+            }
+            i -= size;
+        }
+        unreachable!()
+    }
+
+    /// Returns a random accepted string, or `None` if no string of a
+    /// length with a positive weight is accepted.
+    pub fn sample(&mut self) -> Option<String> {
+        let weights: Vec<f64> = self.lengths.iter().enumerate()
+            .map(|(n, &w)| if self.counts[n][self.start] > 0.0 { w } else { 0.0 }).collect();
+        let mut n = self.rng.choose(&weights)?;
+
+        let mut word = String::new();
+        let mut s = self.start;
+        while n > 0 {
+            let weights: Vec<f64> = self.table[s].iter()
+                .map(|&t| t.map_or(0.0, |t| self.counts[n - 1][t])).collect();
+            let k = self.rng.choose(&weights).unwrap();
+            let c = self.char_in(k);
+            word.push(c);
+            s = self.table[s][k].unwrap();
+            n -= 1;
+        }
+        Some(word)
+    }
+
+    /// Returns whether `word` is accepted, or recognized by the rule of
+    /// the generator.
+    pub fn accepts(&self, word: &str) -> bool {
+        let mut s = self.start;
+        for c in word.chars() {
+            s = match self.table[s][self.classes.class_of(c)] {
+                Some(t) => t,
+                None => return false
+            };
+        }
+        self.accept[s]
+    }
+
+    /// Returns a rejected string one edit away from an accepted one: a
+    /// character is deleted, inserted or replaced. Gives up and returns
+    /// `None` after a few tries, for instance when all strings are
+    /// accepted.
+    pub fn near_miss(&mut self) -> Option<String> {
+        for _ in 0..32 {
+            let word: Vec<char> = match self.sample() {
+                Some(w) => w.chars().collect(),
+                None => return None
+            };
+            let mut edited = word.clone();
+            let i = self.rng.below(word.len() as u64 + 1) as usize;
+            let op = if i == word.len() { 1 } else { self.rng.below(3) };
+            if op == 0 {
+                edited.remove(i);
+            } else {
+                let nonempty: Vec<usize> = (0..self.ranges.len()).filter(|&k| !self.ranges[k].is_empty()).collect();
+                let k = nonempty[self.rng.below(nonempty.len() as u64) as usize];
+                let c = self.char_in(k);
+                if op == 1 {
+                    edited.insert(i, c);
+                } else {
+                    edited[i] = c;
+                }
+            }
+            let edited: String = edited.into_iter().collect();
+            if !self.accepts(&edited) {
+                return Some(edited);
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over the strings accepted by an automaton, or recognized
+/// by one of its rules, in shortlex order: by length, then in the order
+/// of their characters.
+///
+/// Characters that the automaton cannot tell apart are not enumerated
+/// separately: each class of input is represented by one of its
+/// characters, so that `[a-z]+` gives `a`, `aa`, `aaa`, and so on.
+pub struct Strings {
+    // the representative of each non-empty class, in order of characters
+    reps: Vec<(usize, char)>,
+    table: Vec<Vec<Option<StateID>>>,
+    start: StateID,
+    accept: Vec<bool>,
+    max_len: Option<usize>,
+    // can[n][s] is whether an accepting state is reached from s by
+    // exactly n classes, computed for the lengths explored so far
+    can: Vec<Vec<bool>>,
+    infinite: Option<bool>,
+    // the length of the strings being enumerated, and the next one
+    len: usize,
+    next_len: usize,
+    // the depth-first search over the paths of len classes, with the
+    // index of the next representative to try from each state on it
+    stack: Vec<(StateID, usize)>,
+    word: String
+}
+
+impl Strings {
+    /// Enumerates the strings accepted by `nfa`, up to `max_len`
+    /// characters if given.
+    ///
+    /// Fails if the DFA of `nfa` exceeds `limit`.
+    pub fn new(nfa: &NFA, limit: &DfaLimit, max_len: Option<usize>) -> Result<Strings, StateExplosion> {
+        Ok(Strings::build(&nfa.to_dfa_limited(limit)?, None, max_len))
+    }
+
+    /// Enumerates the strings for which `rule` wins in the combined
+    /// automaton `nfa`, like `Generator::for_rule`.
+    pub fn for_rule(nfa: &NFA, limit: &DfaLimit, rule: RuleID, max_len: Option<usize>) -> Result<Strings, StateExplosion> {
+        Ok(Strings::build(&nfa.to_dfa_limited(limit)?, Some(rule), max_len))
+    }
+
+    fn build(dfa: &NFA, rule: Option<RuleID>, max_len: Option<usize>) -> Strings {
+        let classes = dfa.char_classes();
+        let mut reps: Vec<(usize, char)> = (0..classes.len())
+            .filter_map(|k| classes.representative(k).map(|c| (k, c))).collect();
+        reps.sort_by_key(|&(_, c)| c);
+        let accept = accepting(dfa, rule);
+
+        Strings { reps, table: class_table(dfa, &classes), start: dfa.start_state(),
+                  can: vec![accept.clone()], accept, max_len, infinite: None,
+                  len: 0, next_len: 0, stack: Vec::new(), word: String::new() }
+    }
+
+    fn can(&mut self, n: usize, s: StateID) -> bool {
+        while self.can.len() <= n {
+            let level: Vec<bool> = {
+                let last = self.can.last().unwrap();
+                self.table.iter().map(|row| row.iter().any(|&t| t.is_some_and(|t| last[t]))).collect()
+            };
+            self.can.push(level);
+        }
+        self.can[n][s]
+    }
+
+    // whether infinitely many strings are accepted, which is the case
+    // exactly when one of a length in n..2n is, for a DFA of n states
+    fn infinite(&mut self) -> bool {
+        if self.infinite.is_none() {
+            let n = self.table.len();
+            let start = self.start;
+            let res = (n..2 * n).any(|len| self.can(len, start));
+            self.infinite = Some(res);
+        }
+        self.infinite.unwrap()
+    }
+
+    // starts the search for the strings of the next length with any,
+    // returning false if there are no more strings
+    fn next_length(&mut self) -> bool {
+        loop {
+            self.len = self.next_len;
+            self.next_len += 1;
+            if self.max_len.is_some_and(|m| self.len > m) {
+                return false;
+            }
+            // all the strings of a finite language are shorter than the
+            // number of states
+            if self.len >= self.table.len() && !self.infinite() {
+                return false;
+            }
+            let (len, start) = (self.len, self.start);
+            if self.can(len, start) {
+                self.stack.push((start, 0));
+                return true;
+            }
+        }
+    }
+
+    fn pop(&mut self) {
+        self.stack.pop();
+        if !self.stack.is_empty() {
+            self.word.pop();
+        }
+    }
+}
+
+impl Iterator for Strings {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            let (s, i) = match self.stack.last() {
+                Some(&top) => top,
+                None => {
+                    if !self.next_length() {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+
+            // only the paths that reach an accepting state are followed
+            let depth = self.stack.len() - 1;
+            if depth == self.len {
+                let word = self.word.clone();
+                self.pop();
+                if self.accept[s] {
+                    return Some(word);
+                }
+                continue;
+            }
+            if i == self.reps.len() {
+                self.pop();
+                continue;
+            }
+
+            self.stack.last_mut().unwrap().1 += 1;
+            let (k, c) = self.reps[i];
+            if let Some(t) = self.table[s][k] {
+                let remaining = self.len - depth - 1;
+                if self.can(remaining, t) {
+                    self.stack.push((t, 0));
+                    self.word.push(c);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rng() {
+    let mut rng = Rng::new(42);
+    let xs: Vec<u64> = (0..1000).map(|_| rng.below(10)).collect();
+    assert!(xs.iter().all(|&x| x < 10));
+    assert!((0..10).all(|d| xs.iter().filter(|&&x| x == d).count() > 50));
+    assert!((0..1000).map(|_| rng.unit()).all(|x| (0.0..1.0).contains(&x)));
+
+    let mut other = Rng::new(42);
+    assert_eq!(other.below(10), xs[0]);
+    assert_eq!(rng.choose(&[0.0, 0.0]), None);
+    assert_eq!(rng.choose(&[0.0, 1.0, 0.0]), Some(1));
+}
+
+#[test]
+fn test_generator() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let nfa = NFABuilder::build_from_specs(&mut vec![spec(r"if|else"), spec(r"[a-z][a-z0-9]*"),
+                                                     spec(r"[0-9]+(\.[0-9]+)?"), spec(r#""[^"]*""#)]);
+    let limit = DfaLimit::default();
+
+    let mut gen = Generator::new(&nfa, &limit, Lengths::Uniform(1, 8), 7).unwrap();
+    for _ in 0..200 {
+        let w = gen.sample().unwrap();
+        let n = w.chars().count();
+        assert!((1..=8).contains(&n), "{:?}", w);
+        assert!(nfa.simulate(&w), "{:?}", w);
+    }
+
+    // the strings of a rule are tokens of that rule
+    for rule in 0..4 {
+        let mut gen = Generator::for_rule(&nfa, &limit, rule, Lengths::Uniform(0, 6), rule as u64).unwrap();
+        for _ in 0..50 {
+            let w = gen.sample().unwrap();
+            assert_eq!(nfa.longest_match(&w), Some((rule, w.len())), "{:?}", w);
+        }
+    }
+
+    // only the lengths with accepted strings are drawn
+    let mut gen = Generator::for_rule(&nfa, &limit, 0, Lengths::Weights(vec![1.0, 1.0, 0.0, 1.0, 5.0]), 1).unwrap();
+    assert!((0..20).all(|_| gen.sample() == Some("else".to_string())));
+    let mut gen = Generator::for_rule(&nfa, &limit, 0, Lengths::Uniform(5, 10), 1).unwrap();
+    assert_eq!(gen.sample(), None);
+    assert_eq!(gen.near_miss(), None);
+
+    // the same seed gives the same strings
+    let mut g1 = Generator::new(&nfa, &limit, Lengths::Uniform(0, 20), 99).unwrap();
+    let mut g2 = Generator::new(&nfa, &limit, Lengths::Uniform(0, 20), 99).unwrap();
+    for _ in 0..10 {
+        assert_eq!(g1.sample(), g2.sample());
+    }
+
+    // the DFA is built within the limit
+    let small = DfaLimit { max_states: 3, max_bytes: usize::MAX };
+    assert!(Generator::new(&nfa, &small, Lengths::Uniform(0, 4), 1).is_err());
+}
+
+#[test]
+fn test_near_miss() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let nfa = NFABuilder::build_from_spec(rules::regex_to_nfa_spec(&Expr::parse(r"[0-9]+(\.[0-9]+)?").unwrap()));
+    let limit = DfaLimit::default();
+    let mut gen = Generator::new(&nfa, &limit, Lengths::Uniform(1, 6), 3).unwrap();
+    for _ in 0..100 {
+        let w = gen.near_miss().unwrap();
+        assert!(!nfa.simulate(&w), "{:?}", w);
+        assert!(w.chars().count() <= 7, "{:?}", w);
+    }
+
+    // no string is rejected by .*
+    let all = NFABuilder::build_from_spec(rules::regex_to_nfa_spec(&Expr::parse(r"(?s).*").unwrap()));
+    assert_eq!(Generator::new(&all, &limit, Lengths::Uniform(0, 4), 3).unwrap().near_miss(), None);
+}
+
+#[test]
+fn test_strings() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let limit = DfaLimit::default();
+    let strings = |p, max_len| Strings::new(&NFABuilder::build_from_spec(spec(p)), &limit, max_len).unwrap().collect::<Vec<String>>();
+
+    assert_eq!(strings(r"b|ab*|c", Some(4)), vec!["a", "b", "c", "ab", "abb", "abbb"]);
+    assert_eq!(strings(r"if|else|for", None), vec!["if", "for", "else"]);
+    assert_eq!(strings(r"a{2}[x-z]?", Some(2)), vec!["aa"]);
+
+    // infinite languages are enumerated lazily
+    let nfa = NFABuilder::build_from_spec(spec(r"[a-z][0-9]*"));
+    assert_eq!(Strings::new(&nfa, &limit, None).unwrap().take(4).collect::<Vec<String>>(), vec!["a", "a0", "a00", "a000"]);
+    let nfa = NFABuilder::build_from_spec(spec(r"(aa)*"));
+    assert_eq!(Strings::new(&nfa, &limit, None).unwrap().take(3).collect::<Vec<String>>(), vec!["", "aa", "aaaa"]);
+
+    // the strings of a rule are the ones it wins
+    let nfa = NFABuilder::build_from_specs(&mut vec![spec(r"if"), spec(r"[a-z]+")]);
+    let ids: Vec<String> = Strings::for_rule(&nfa, &limit, 1, Some(2)).unwrap().collect();
+    assert_eq!(ids, vec!["a", "f", "i", "aa", "af", "ai", "fa", "ff", "fi", "ia", "ii"]);
+    assert_eq!(Strings::for_rule(&nfa, &limit, 0, None).unwrap().collect::<Vec<String>>(), vec!["if"]);
+    let shadowed = NFABuilder::build_from_specs(&mut vec![spec(r"[a-z]+"), spec(r"if")]);
+    assert_eq!(Strings::for_rule(&shadowed, &limit, 1, None).unwrap().next(), None);
+
+    // the DFA is built within the limit
+    let small = DfaLimit { max_states: 2, max_bytes: usize::MAX };
+    assert!(Strings::for_rule(&shadowed, &small, 0, None).is_err());
+}
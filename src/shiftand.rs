@@ -0,0 +1,164 @@
+//
+// shiftand.rs
+// Bit-parallel simulation of position automata
+//
+// The states of the position automaton of the rules, other than its
+// start state, are the bits of a u64. A step by a character c keeps the
+// positions that can follow one in the current set and whose label
+// matches c. The positions that can follow a set are computed a byte of
+// the set at a time, from tables of the union of the follow sets of the
+// positions in each possible byte.
+//
+
+use classes::CharClasses;
+use nfa::Label;
+use nfa::Positions;
+use nfa::RuleID;
+use nfa::Spec;
+
+/// Maximum number of symbol positions in the rules of a `ShiftAnd`.
+pub const MAX_POSITIONS: usize = 64;
+
+/// A matcher for rules with at most `MAX_POSITIONS` symbol positions in
+/// all, which needs no determinization.
+pub struct ShiftAnd {
+    classes: CharClasses,
+    // positions matching the characters of each class
+    masks: Vec<u64>,
+    first: u64,
+    // last positions of each rule
+    last: Vec<u64>,
+    // rule matching the empty string
+    nullable: Option<RuleID>,
+    // follow[i][b] holds the positions that can follow the ones in byte
+    // i of a set, when that byte is b
+    follow: Vec<[u64; 256]>
+}
+
+impl ShiftAnd {
+    /// Builds the matcher for the rules in `specs`, tagged with their
+    /// indices, or returns `None` if they have too many positions or an
+    /// intersection or complement.
+    pub fn new(specs: Vec<Spec>) -> Option<ShiftAnd> {
+        if specs.iter().any(|s| s.has_boolean_ops()) ||
+            specs.iter().map(|s| s.positions()).sum::<usize>() > MAX_POSITIONS {
+            return None;
+        }
+        let mut pos = Positions::new();
+        let lins: Vec<_> = specs.into_iter().map(|s| pos.linearize(s)).collect();
+
+        let classes = CharClasses::new(pos.labels.iter());
+        let masks = (0..classes.len()).map(|k| {
+            let rep = classes.representative(k);
+            bits(pos.labels.iter().enumerate()
+                 .filter(|&(_, l)| *l == Label::Any || rep.is_some_and(|c| l.matches(c)))
+                 .map(|(p, _)| p))
+        }).collect();
+
+        let follow_sets: Vec<u64> = pos.follow.iter().map(|f| bits(f.iter().cloned())).collect();
+        let mut follow = vec![[0u64; 256]; pos.labels.len().div_ceil(8)];
+        for (i, table) in follow.iter_mut().enumerate() {
+            for (b, entry) in table.iter_mut().enumerate() {
+                *entry = (0..8).filter(|j| b & (1 << j) != 0)
+                    .filter_map(|j| follow_sets.get(8 * i + j)).fold(0, |acc, f| acc | f);
+            }
+        }
+
+        Some(ShiftAnd {
+            classes,
+            masks,
+            first: lins.iter().fold(0, |acc, l| acc | bits(l.first.iter().cloned())),
+            last: lins.iter().map(|l| bits(l.last.iter().cloned())).collect(),
+            nullable: lins.iter().position(|l| l.nullable),
+            follow
+        })
+    }
+
+    // the positions that can follow one in set
+    fn follow(&self, set: u64) -> u64 {
+        let mut res = 0;
+        for (i, table) in self.follow.iter().enumerate() {
+            res |= table[((set >> (8 * i)) & 0xFF) as usize];
+        }
+        res
+    }
+
+    // the set reached from set (the start state if None) by c
+    fn step(&self, set: Option<u64>, c: char) -> u64 {
+        let targets = match set {
+            None => self.first,
+            Some(set) => self.follow(set)
+        };
+        targets & self.masks[self.classes.class_of(c)]
+    }
+
+    // highest priority rule recognized in set
+    fn rule(&self, set: u64) -> Option<RuleID> {
+        self.last.iter().position(|&l| l & set != 0)
+    }
+
+    pub fn simulate(&self, word: &str) -> bool {
+        let mut set = None;
+        for c in word.chars() {
+            let next = self.step(set, c);
+            if next == 0 {
+                return false;
+            }
+            set = Some(next);
+        }
+        match set {
+            None => self.nullable.is_some(),
+            Some(set) => self.rule(set).is_some()
+        }
+    }
+
+    /// Finds the longest prefix of `input` recognized by the rules, like
+    /// `NFA::longest_match`.
+    pub fn longest_match(&self, input: &str) -> Option<(RuleID, usize)> {
+        let mut set = None;
+        let mut last = self.nullable.map(|r| (r, 0));
+
+        for (i, c) in input.char_indices() {
+            let next = self.step(set, c);
+            if next == 0 {
+                break;
+            }
+            if let Some(r) = self.rule(next) {
+                last = Some((r, i + c.len_utf8()));
+            }
+            set = Some(next);
+        }
+
+        last
+    }
+}
+
+fn bits<I: Iterator<Item=usize>>(positions: I) -> u64 {
+    positions.fold(0, |acc, p| acc | 1 << p)
+}
+
+#[test]
+fn test_shift_and() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let rule_sets = [vec![r"if", r"[a-z][a-z0-9]*", r"[0-9]+", r"."],
+                     vec![r"(a|b)*a(a|b){20}"],
+                     vec![r"(ab|c)*d?", r"a.*z"]];
+    let words = ["if", "iff", "x9", "42", "!", "é", "", "abcd", "ababc", "a!z",
+                 "abbbbbbbbbbbbbbbbbbbbb", "aabbbbbbbbbbbbbbbbbbbbb", "bbbbbbbbbbbbbbbbbbbbbbbbbbbbb"];
+
+    for patterns in rule_sets.iter() {
+        let matcher = ShiftAnd::new(patterns.iter().map(|p| spec(p)).collect()).unwrap();
+        let nfa = NFABuilder::build_from_specs(&mut patterns.iter().map(|p| spec(p)).collect());
+        for w in words.iter() {
+            assert_eq!(matcher.simulate(w), nfa.simulate(w), "{:?} on {}", patterns, w);
+            assert_eq!(matcher.longest_match(w), nfa.longest_match(w), "{:?} on {}", patterns, w);
+        }
+    }
+
+    assert!(ShiftAnd::new(vec![spec(r"[a-z]{65}")]).is_none());
+    assert!(ShiftAnd::new(vec![spec(r"[a-z]{64}")]).is_some());
+}
@@ -0,0 +1,76 @@
+//
+// stateset.rs
+// Sparse sets of states
+//
+
+use std::slice;
+
+use nfa::StateID;
+
+/// A set of the states `0..capacity` of an automaton, with constant-time
+/// insertion, membership test and clearing, and iteration in insertion
+/// order (the sparse set of Briggs and Torczon). It is meant to be
+/// cleared and reused, to avoid allocating a set for each input
+/// character.
+pub struct StateSet {
+    // the members, in insertion order
+    dense: Vec<StateID>,
+    // sparse[s] is the position of s in dense, if s is a member
+    sparse: Vec<usize>
+}
+
+impl StateSet {
+    pub fn new(capacity: usize) -> StateSet {
+        StateSet { dense: Vec::with_capacity(capacity), sparse: vec![0; capacity] }
+    }
+
+    pub fn contains(&self, s: StateID) -> bool {
+        let i = self.sparse[s];
+        i < self.dense.len() && self.dense[i] == s
+    }
+
+    /// Adds `s` to the set, returning whether it was not already there.
+    pub fn insert(&mut self, s: StateID) -> bool {
+        if self.contains(s) {
+            return false;
+        }
+        self.sparse[s] = self.dense.len();
+        self.dense.push(s);
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.dense.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, StateID> {
+        self.dense.iter()
+    }
+}
+
+#[test]
+fn test_state_set() {
+    let mut set = StateSet::new(10);
+    assert!(set.is_empty());
+    assert!(set.insert(7));
+    assert!(set.insert(2));
+    assert!(!set.insert(7));
+    assert!(set.contains(2));
+    assert!(!set.contains(0));
+    assert_eq!(set.len(), 2);
+    assert_eq!(set.iter().cloned().collect::<Vec<StateID>>(), vec![7, 2]);
+
+    set.clear();
+    assert!(set.is_empty());
+    assert!(!set.contains(7));
+    assert!(set.insert(0));
+    assert_eq!(set.iter().cloned().collect::<Vec<StateID>>(), vec![0]);
+}
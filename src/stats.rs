@@ -0,0 +1,133 @@
+//
+// stats.rs
+// Statistics about the automata built for a lexer specification
+//
+
+use std::fmt;
+use std::time::Duration;
+use std::time::Instant;
+
+use codegen::Options;
+use dfa::DFA;
+use diagnostics;
+use diagnostics::Diagnostic;
+use parser::LexSpec;
+
+/// Sizes of the automata built for a start condition, and the time
+/// taken by each phase of their construction.
+pub struct Stats {
+    pub condition: String,
+    pub rules: usize,
+    pub nfa_states: usize,
+    pub nfa_transitions: usize,
+    /// DFA states after subset construction.
+    pub dfa_states: usize,
+    /// DFA states after minimization.
+    pub min_dfa_states: usize,
+    /// Transitions in the minimized DFA.
+    pub dfa_transitions: usize,
+    pub input_classes: usize,
+    /// Size of the transition and accept tables for the minimized DFA,
+    /// with a dense table indexed by state and input class.
+    pub table_bytes: usize,
+    pub nfa_time: Duration,
+    pub dfa_time: Duration,
+    pub minimize_time: Duration,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1.0e6
+}
+
+/// Builds the automata for start condition `cond` of `spec`, measuring
+/// them, with the same options as `codegen::generate_with`.
+///
+/// Fails if the DFA exceeds `options.limit`.
+pub fn condition_stats(spec: &LexSpec, cond: usize, options: &Options) -> Result<Stats, Diagnostic> {
+    let rules = spec.active_rules(cond).len();
+    let explosion = |e| diagnostics::state_explosion(spec, cond, &e, &options.limit);
+
+    let t0 = Instant::now();
+    let nfa = spec.condition_nfa_limited(cond, options.utf8_bytes, options.construction, &options.limit)
+        .map_err(&explosion)?;
+    let t1 = Instant::now();
+    let dfa = nfa.to_dfa_limited(&options.limit).map_err(&explosion)?;
+    let t2 = Instant::now();
+    let min = dfa.minimize();
+    let t3 = Instant::now();
+
+    let table = DFA::new(&min);
+
+    Ok(Stats {
+        condition: spec.conditions[cond].name.clone(),
+        rules,
+        nfa_states: nfa.num_states(),
+        nfa_transitions: nfa.num_transitions(),
+        dfa_states: dfa.num_states(),
+        min_dfa_states: min.num_states(),
+        dfa_transitions: min.num_transitions(),
+        input_classes: table.classes().len(),
+        table_bytes: table.table_bytes(),
+        nfa_time: t1 - t0,
+        dfa_time: t2 - t1,
+        minimize_time: t3 - t2,
+    })
+}
+
+/// Builds the automata for all start conditions of `spec`, measuring them.
+pub fn spec_stats(spec: &LexSpec, options: &Options) -> Result<Vec<Stats>, Diagnostic> {
+    (0 .. spec.conditions.len()).map(|c| condition_stats(spec, c, options)).collect()
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "start condition {}: {} rules", self.condition, self.rules)?;
+        writeln!(f, "  {} NFA states, {} transitions", self.nfa_states, self.nfa_transitions)?;
+        writeln!(f, "  {} DFA states, {} after minimization, {} transitions",
+                 self.dfa_states, self.min_dfa_states, self.dfa_transitions)?;
+        writeln!(f, "  {} input equivalence classes", self.input_classes)?;
+        writeln!(f, "  {} bytes in tables", self.table_bytes)?;
+        writeln!(f, "  construction: NFA {:.3} ms, DFA {:.3} ms, minimization {:.3} ms",
+                 millis(self.nfa_time), millis(self.dfa_time), millis(self.minimize_time))
+    }
+}
+
+#[test]
+fn test_stats() {
+    use nfa::Construction;
+    use nfa::DfaLimit;
+    use parser::parse_spec;
+
+    let spec = parse_spec(r#"
+%x STR
+%%
+(a|b)*b     return Some(Token::B);
+<STR>x      return Some(Token::X);
+"#).unwrap();
+    let stats = spec_stats(&spec, &Options::default()).unwrap();
+
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].condition, "INITIAL");
+    assert_eq!(stats[0].rules, 1);
+    assert_eq!(stats[0].dfa_states, 3);
+    assert_eq!(stats[0].min_dfa_states, 2);
+    assert_eq!(stats[0].dfa_transitions, 4);
+    assert_eq!(stats[0].input_classes, 3);
+    assert_eq!(stats[0].table_bytes, 2 * 3 + 2);
+    assert!(stats[0].nfa_states > stats[0].dfa_states);
+    assert_eq!(stats[1].min_dfa_states, 2);
+
+    let text = format!("{}", stats[1]);
+    assert!(text.starts_with("start condition STR: 1 rules\n"));
+    assert!(text.contains("  2 DFA states, 2 after minimization, 1 transitions\n"));
+
+    // the automata of the other options are measured, within their limit
+    let accents = parse_spec("%%\né+  return Some(Token::E);\n").unwrap();
+    assert_eq!(condition_stats(&accents, 0, &Options::default()).unwrap().min_dfa_states, 2);
+    let bytes = Options { utf8_bytes: true, ..Options::default() };
+    assert_eq!(condition_stats(&accents, 0, &bytes).unwrap().min_dfa_states, 3);
+    let glushkov = condition_stats(&spec, 0, &Options { construction: Construction::Glushkov, ..Options::default() }).unwrap();
+    assert_eq!(glushkov.nfa_states, 4);
+    let options = Options { limit: DfaLimit { max_states: 2, max_bytes: 1 << 30 }, ..Options::default() };
+    assert_eq!(condition_stats(&spec, 0, &options).err().unwrap().rule, 0);
+}
@@ -0,0 +1,422 @@
+//
+// tdfa.rs
+// Tagged DFA: determinization with capture positions
+//
+// Each state of a tagged DFA is an ordered list of configurations: an NFA
+// state, in the priority order of the Pike VM, with a register holding
+// each capture slot, or no register if the slot has not been set on the
+// way to it. The transitions carry operations on the registers that store
+// the current position in the slots set by the group states crossed, and
+// move the other slots to the registers of the target state. Matching is
+// then a DFA walk plus these operations, without keeping threads.
+//
+// Two lists of configurations lead to the same state when they have the
+// same NFA states, in the same order, with the same slots set; only the
+// states with transitions on characters or a rule are kept, as the others
+// make no difference after the epsilon closure.
+//
+
+use std::collections::HashMap;
+use std::mem;
+
+use classes::CharClasses;
+use dfa::DEAD;
+use nfa::DfaLimit;
+use nfa::Label;
+use nfa::NFA;
+use nfa::RuleID;
+use nfa::StateExplosion;
+use nfa::StateID;
+use pikevm;
+use pikevm::Captures;
+use stateset::StateSet;
+
+/// Identifier of a register of a `TaggedDFA`.
+pub type RegID = usize;
+
+/// An operation on the registers, done when a transition is taken.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RegOp {
+    /// Stores the position after the character read in a register.
+    Set(RegID),
+    /// Copies the second register into the first.
+    Copy(RegID, RegID)
+}
+
+impl RegOp {
+    fn dst(&self) -> RegID {
+        match *self {
+            RegOp::Set(r) | RegOp::Copy(r, _) => r
+        }
+    }
+}
+
+// value of a slot while following the epsilon transitions from a state
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Value {
+    Unset,
+    Position,
+    Reg(RegID)
+}
+
+// an NFA state with the register of each slot
+type Config = (StateID, Vec<Option<RegID>>);
+
+/// A DFA with registers for the capture slots of the rules of an NFA
+/// built by Thompson's construction, which finds the same captures as
+/// `PikeVM`.
+pub struct TaggedDFA {
+    classes: CharClasses,
+    nslots: usize,
+    nregs: usize,
+    start_ops: Vec<RegOp>,
+    // transitions of state s are at s * classes.len() .. (s + 1) * classes.len(),
+    // with the operations of each one
+    table: Vec<StateID>,
+    ops: Vec<Vec<RegOp>>,
+    accept: Vec<Option<RuleID>>,
+    // the register of each slot for the rule recognized
+    finals: Vec<Vec<Option<RegID>>>
+}
+
+struct Builder<'a> {
+    nfa: &'a NFA,
+    kernels: Vec<Vec<Config>>,
+    state_map: HashMap<Vec<(StateID, Vec<bool>)>, StateID>,
+    nregs: usize
+}
+
+impl<'a> Builder<'a> {
+    // adds the configurations for s and the states reached from it by
+    // epsilon transitions, in order of priority, like PikeVM::add_thread
+    fn closure(&self, list: &mut Vec<(StateID, Vec<Value>)>, seen: &mut StateSet, s: StateID, values: Vec<Value>) {
+        let mut stack = vec![(s, values)];
+        while let Some((s, mut values)) = stack.pop() {
+            if !seen.insert(s) {
+                continue;
+            }
+            let st = self.nfa.get_state(s).unwrap();
+            if let Some(slot) = st.save() {
+                values[slot] = Value::Position;
+            }
+            for t in st.transitions().iter().rev() {
+                if *t.label() == Label::Epsilon {
+                    stack.push((t.target(), values.clone()));
+                }
+            }
+            if st.rule().is_some() || st.transitions().iter().any(|t| *t.label() != Label::Epsilon) {
+                list.push((s, values));
+            }
+        }
+    }
+
+    fn fresh(&mut self) -> RegID {
+        self.nregs += 1;
+        self.nregs - 1
+    }
+
+    // returns the state for the configurations in list, adding it if it
+    // is new, and the operations that move the values into its registers,
+    // to be done in parallel
+    fn target(&mut self, list: Vec<(StateID, Vec<Value>)>) -> (StateID, Vec<RegOp>) {
+        let key: Vec<(StateID, Vec<bool>)> = list.iter()
+            .map(|&(s, ref values)| (s, values.iter().map(|&v| v != Value::Unset).collect())).collect();
+        let mut ops = Vec::new();
+
+        if let Some(&t) = self.state_map.get(&key) {
+            for ((_, values), (_, regs)) in list.iter().zip(self.kernels[t].iter()) {
+                for (&v, &r) in values.iter().zip(regs.iter()) {
+                    match (v, r) {
+                        (Value::Position, Some(dst)) => ops.push(RegOp::Set(dst)),
+                        (Value::Reg(src), Some(dst)) if src != dst => ops.push(RegOp::Copy(dst, src)),
+                        _ => ()
+                    }
+                }
+            }
+            return (t, ops);
+        }
+
+        // a register of the source keeps its value if a single slot of
+        // the new state takes it
+        let mut used = Vec::new();
+        let mut kernel = Vec::with_capacity(list.len());
+        for (s, values) in list {
+            let regs = values.iter().map(|&v| match v {
+                Value::Unset => None,
+                Value::Position => {
+                    let dst = self.fresh();
+                    ops.push(RegOp::Set(dst));
+                    Some(dst)
+                },
+                Value::Reg(src) if !used.contains(&src) => {
+                    used.push(src);
+                    Some(src)
+                },
+                Value::Reg(src) => {
+                    let dst = self.fresh();
+                    ops.push(RegOp::Copy(dst, src));
+                    Some(dst)
+                }
+            }).collect();
+            kernel.push((s, regs));
+        }
+        let t = self.kernels.len();
+        self.kernels.push(kernel);
+        self.state_map.insert(key, t);
+        (t, ops)
+    }
+}
+
+// orders operations meant to be done in parallel so that no register is
+// overwritten before it is read, saving a register in tmp to break cycles
+fn sequential(mut pending: Vec<RegOp>, tmp: RegID, uses_tmp: &mut bool) -> Vec<RegOp> {
+    let mut res = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let read = |ops: &Vec<RegOp>, r: RegID| ops.iter().any(|op| match *op {
+            RegOp::Copy(_, src) => src == r,
+            RegOp::Set(_) => false
+        });
+        match pending.iter().position(|op| !read(&pending, op.dst())) {
+            Some(i) => res.push(pending.remove(i)),
+            None => {
+                // all the operations left are copies in cycles
+                let r = pending[0].dst();
+                res.push(RegOp::Copy(tmp, r));
+                *uses_tmp = true;
+                for op in pending.iter_mut() {
+                    if let RegOp::Copy(dst, src) = *op {
+                        if src == r {
+                            *op = RegOp::Copy(dst, tmp);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    res
+}
+
+impl TaggedDFA {
+    /// Builds the tagged DFA of `nfa`, without limits.
+    pub fn new(nfa: &NFA) -> TaggedDFA {
+        // cannot fail without limits
+        TaggedDFA::build(nfa, nfa.char_classes(), &DfaLimit::unlimited()).unwrap()
+    }
+
+    /// Builds the tagged DFA of `nfa` with transitions indexed by
+    /// `classes`, which must not put together characters that `nfa`
+    /// tells apart, or fails if it exceeds `limit`. The rule of the
+    /// failure is never known.
+    pub fn build(nfa: &NFA, classes: CharClasses, limit: &DfaLimit) -> Result<TaggedDFA, StateExplosion> {
+        let nclasses = classes.len();
+        let reps: Vec<Option<char>> = (0..nclasses).map(|k| classes.representative(k)).collect();
+        let nslots = pikevm::num_slots(nfa);
+        let mut builder = Builder { nfa, kernels: Vec::new(), state_map: HashMap::new(), nregs: 0 };
+        let mut seen = StateSet::new(nfa.num_states());
+
+        let mut list = Vec::new();
+        builder.closure(&mut list, &mut seen, nfa.start_state(), vec![Value::Unset; nslots]);
+        let (_, start_ops) = builder.target(list);
+
+        let mut table = Vec::new();
+        let mut ops = Vec::new();
+        let mut targets = Vec::new();
+        // states are numbered in the order they are found, so the states
+        // still to be processed are the ones after state
+        let mut state = 0;
+        while state < builder.kernels.len() {
+            let bytes = table.len() * mem::size_of::<StateID>() + builder.nregs * mem::size_of::<RegOp>();
+            if builder.kernels.len() > limit.max_states || bytes > limit.max_bytes {
+                return Err(StateExplosion { states: builder.kernels.len(), bytes, rule: None });
+            }
+            let kernel = builder.kernels[state].clone();
+            for rep in reps.iter() {
+                let c = match *rep {
+                    Some(c) => c,
+                    None => {
+                        table.push(DEAD);
+                        ops.push(Vec::new());
+                        continue;
+                    }
+                };
+                let mut list = Vec::new();
+                seen.clear();
+                for &(s, ref regs) in kernel.iter() {
+                    targets.clear();
+                    nfa.get_state(s).unwrap().step_each(c, |t| targets.push(t));
+                    let values: Vec<Value> = regs.iter().map(|r| r.map_or(Value::Unset, Value::Reg)).collect();
+                    for &t in targets.iter() {
+                        builder.closure(&mut list, &mut seen, t, values.clone());
+                    }
+                }
+                if list.is_empty() {
+                    table.push(DEAD);
+                    ops.push(Vec::new());
+                } else {
+                    let (t, t_ops) = builder.target(list);
+                    table.push(t);
+                    ops.push(t_ops);
+                }
+            }
+            state += 1;
+        }
+
+        // the rule recognized by a state is the one of its first
+        // configuration with the lowest rule
+        let mut accept = Vec::with_capacity(builder.kernels.len());
+        let mut finals = Vec::with_capacity(builder.kernels.len());
+        for kernel in builder.kernels.iter() {
+            let rule = kernel.iter().filter_map(|&(s, _)| nfa.get_state(s).unwrap().rule()).min();
+            let regs = rule.and_then(|r| kernel.iter().find(|&&(s, _)| nfa.get_state(s).unwrap().rule() == Some(r)))
+                .map_or(vec![None; nslots], |(_, regs)| regs.clone());
+            accept.push(rule);
+            finals.push(regs);
+        }
+
+        let tmp = builder.nregs;
+        let mut uses_tmp = false;
+        let start_ops = sequential(start_ops, tmp, &mut uses_tmp);
+        let ops = ops.into_iter().map(|o| sequential(o, tmp, &mut uses_tmp)).collect();
+
+        Ok(TaggedDFA {
+            classes,
+            nslots,
+            nregs: if uses_tmp { tmp + 1 } else { tmp },
+            start_ops,
+            table,
+            ops,
+            accept,
+            finals
+        })
+    }
+
+    pub fn classes(&self) -> &CharClasses {
+        &self.classes
+    }
+
+    /// Returns the number of states; the start state is state 0.
+    pub fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    pub fn num_registers(&self) -> usize {
+        self.nregs
+    }
+
+    /// Returns the number of capture slots, two for each group.
+    pub fn num_slots(&self) -> usize {
+        self.nslots
+    }
+
+    /// Returns the operations to do before reading the input, with the
+    /// position 0.
+    pub fn start_ops(&self) -> &[RegOp] {
+        &self.start_ops
+    }
+
+    /// Returns the state reached from `s` by a character of class `k`,
+    /// or `DEAD`.
+    pub fn next_class(&self, s: StateID, k: usize) -> StateID {
+        self.table[s * self.classes.len() + k]
+    }
+
+    /// Returns the operations of the transition from `s` by class `k`,
+    /// to be done in order.
+    pub fn ops(&self, s: StateID, k: usize) -> &[RegOp] {
+        &self.ops[s * self.classes.len() + k]
+    }
+
+    pub fn accept(&self, s: StateID) -> Option<RuleID> {
+        self.accept[s]
+    }
+
+    /// Returns the register holding each slot for the rule recognized
+    /// in `s`, or `None` for the slots it leaves unset.
+    pub fn final_registers(&self, s: StateID) -> &[Option<RegID>] {
+        &self.finals[s]
+    }
+
+    fn run(&self, ops: &[RegOp], regs: &mut [Option<usize>], pos: usize) {
+        for op in ops.iter() {
+            match *op {
+                RegOp::Set(dst) => regs[dst] = Some(pos),
+                RegOp::Copy(dst, src) => regs[dst] = regs[src]
+            }
+        }
+    }
+
+    fn captures_at(&self, s: StateID, regs: &[Option<usize>], pos: usize) -> Option<Captures> {
+        self.accept[s].map(|rule| {
+            let slots: Vec<Option<usize>> = self.finals[s].iter().map(|r| r.and_then(|r| regs[r])).collect();
+            Captures::from_slots(rule, &slots, pos)
+        })
+    }
+
+    /// Finds the longest prefix of `input` recognized by the automaton,
+    /// with the spans of the groups of the rule recognized, like
+    /// `PikeVM::captures`.
+    pub fn captures(&self, input: &str) -> Option<Captures> {
+        let mut regs = vec![None; self.nregs];
+        let mut s = 0;
+        self.run(&self.start_ops, &mut regs, 0);
+        let mut last = self.captures_at(s, &regs, 0);
+
+        for (i, c) in input.char_indices() {
+            let pos = i + c.len_utf8();
+            let k = self.classes.class_of(c);
+            let next = self.next_class(s, k);
+            if next == DEAD {
+                break;
+            }
+            self.run(self.ops(s, k), &mut regs, pos);
+            s = next;
+            if let Some(caps) = self.captures_at(s, &regs, pos) {
+                last = Some(caps);
+            }
+        }
+
+        last
+    }
+}
+
+#[test]
+fn test_sequential() {
+    let mut uses_tmp = false;
+    let ops = sequential(vec![RegOp::Copy(1, 0), RegOp::Set(0), RegOp::Copy(2, 1)], 9, &mut uses_tmp);
+    assert_eq!(ops, vec![RegOp::Copy(2, 1), RegOp::Copy(1, 0), RegOp::Set(0)]);
+    assert!(!uses_tmp);
+
+    // swapping two registers needs the temporary one
+    let ops = sequential(vec![RegOp::Copy(0, 1), RegOp::Copy(1, 0)], 9, &mut uses_tmp);
+    assert_eq!(ops, vec![RegOp::Copy(9, 0), RegOp::Copy(0, 1), RegOp::Copy(1, 9)]);
+    assert!(uses_tmp);
+}
+
+#[test]
+fn test_tagged_dfa() {
+    use nfa::NFABuilder;
+    use pikevm::PikeVM;
+    use regex_syntax::Expr;
+    use rules;
+
+    let spec = |p| rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+    let rule_sets = [vec![r"([0-9]+)(\.([0-9]+))?(e([0-9]+))?", r#""([^"]*)""#, r"[a-z]+"],
+                     vec![r"(?:([a-z])[0-9])+"],
+                     vec![r"(a*)(a*)b", r"(a|ab)(c|bcd)(d*)", r"((a)|b)+"],
+                     vec![r"(?P<key>[a-z]+)=(?P<value>[a-z0-9]*)", r"(?P<word>[a-z]+)", r"(.)"]];
+    let words = ["3.14e10+x", "42;", "\"héllo\" x", "abc1", "!", "", "a1b2c3", "aab", "abcd", "abbab",
+                 "b", "ab", "key=v4l", "key=", "é"];
+
+    for patterns in rule_sets.iter() {
+        let nfa = NFABuilder::build_from_specs(&mut patterns.iter().map(|p| spec(p)).collect());
+        let vm = PikeVM::new(&nfa);
+        let tdfa = TaggedDFA::new(&nfa);
+        for w in words.iter() {
+            assert_eq!(tdfa.captures(w), vm.captures(w), "{:?} on {}", patterns, w);
+        }
+    }
+
+    let nfa = NFABuilder::build_from_spec(spec(r"(a|b)*a(a|b){8}"));
+    let limit = DfaLimit { max_states: 100, max_bytes: 1 << 30 };
+    assert!(TaggedDFA::build(&nfa, nfa.char_classes(), &limit).is_err());
+}
@@ -0,0 +1,232 @@
+//
+// utf8.rs
+// Translation of character-level specs into byte-level specs
+//
+// A byte-level automaton reads the UTF-8 encoding of its input one byte
+// at a time. Its labels use the characters U+0000 to U+00FF to stand for
+// the bytes 0x00 to 0xFF, so it can be built, determinized and minimized
+// like any other automaton.
+//
+
+use std::char;
+use std::collections::HashMap;
+
+use arena::SpecArena;
+use arena::SpecID;
+use arena::SpecNode;
+use nfa::Label;
+use nfa::Spec;
+
+// boundaries between the lengths of UTF-8 encodings
+const LENGTH_LIMITS: [u32; 3] = [0x7F, 0x7FF, 0xFFFF];
+
+/// Splits the characters in `lo..hi` into sequences of byte ranges, such
+/// that the UTF-8 encodings of those characters are exactly the byte
+/// strings matched by one of the sequences. The sequences are in order.
+pub fn utf8_sequences(lo: char, hi: char) -> Vec<Vec<(u8, u8)>> {
+    let mut res = Vec::new();
+    let mut stack = vec![(lo as u32, hi as u32)];
+
+    while let Some((lo, mut hi)) = stack.pop() {
+        'split: loop {
+            // surrogates are not characters
+            if lo <= 0xD7FF && hi >= 0xE000 {
+                stack.push((0xE000, hi));
+                hi = 0xD7FF;
+                continue 'split;
+            }
+            // all characters must have encodings of the same length
+            for &max in LENGTH_LIMITS.iter() {
+                if lo <= max && max < hi {
+                    stack.push((max + 1, hi));
+                    hi = max;
+                    continue 'split;
+                }
+            }
+            // continuation bytes must cover their whole range, except for
+            // the bytes before the first that differs
+            for i in 1..4 {
+                let m = (1u32 << (6 * i)) - 1;
+                if lo & !m != hi & !m {
+                    if lo & m != 0 {
+                        stack.push(((lo | m) + 1, hi));
+                        hi = lo | m;
+                        continue 'split;
+                    }
+                    if hi & m != m {
+                        stack.push((hi & !m, hi));
+                        hi = (hi & !m) - 1;
+                        continue 'split;
+                    }
+                }
+            }
+
+            let (mut blo, mut bhi) = ([0; 4], [0; 4]);
+            let n = encode(lo, &mut blo);
+            encode(hi, &mut bhi);
+            res.push((0..n).map(|i| (blo[i], bhi[i])).collect());
+            break;
+        }
+    }
+
+    res
+}
+
+fn encode(c: u32, buf: &mut [u8; 4]) -> usize {
+    // safe to unwrap because the ranges split do not include surrogates
+    char::from_u32(c).unwrap().encode_utf8(buf).len()
+}
+
+fn byte_label(lo: u8, hi: u8) -> Label {
+    if lo == hi {
+        Label::Symbol(lo as char)
+    } else {
+        Label::Class(vec![(lo as char, hi as char)])
+    }
+}
+
+fn sequence_spec_id(arena: &mut SpecArena, seq: &[(u8, u8)]) -> SpecID {
+    let mut s = arena.single(byte_label(seq[0].0, seq[0].1));
+    for &(lo, hi) in seq.iter().skip(1) {
+        let next = arena.single(byte_label(lo, hi));
+        s = arena.concat(s, next);
+    }
+    s
+}
+
+// union of the byte sequences for all characters in ranges
+fn ranges_spec_id(arena: &mut SpecArena, ranges: &[(char, char)]) -> SpecID {
+    let mut seqs = ranges.iter().flat_map(|&(lo, hi)| utf8_sequences(lo, hi).into_iter());
+    // safe to unwrap because a class has at least one range
+    let mut s = sequence_spec_id(arena, &seqs.next().unwrap());
+    for seq in seqs {
+        let next = sequence_spec_id(arena, &seq);
+        s = arena.union(s, next);
+    }
+    s
+}
+
+/// Converts a character-level spec into a byte-level spec matching the
+/// UTF-8 encodings of the same strings.
+pub fn utf8_spec(s: Spec) -> Spec {
+    let mut arena = SpecArena::new();
+    let id = arena.from_spec(&s);
+    let bytes = utf8_spec_id(&mut arena, id);
+    arena.to_spec(bytes)
+}
+
+/// Converts the character-level node `id` of `arena` into a byte-level
+/// node of the same arena, like `utf8_spec`. Shared nodes are converted
+/// once, and counted repetitions are kept as such.
+pub fn utf8_spec_id(arena: &mut SpecArena, id: SpecID) -> SpecID {
+    utf8_node(arena, id, &mut HashMap::new())
+}
+
+// utf8_spec_id, with the nodes already converted in done
+fn utf8_node(arena: &mut SpecArena, id: SpecID, done: &mut HashMap<SpecID, SpecID>) -> SpecID {
+    if let Some(&res) = done.get(&id) {
+        return res;
+    }
+    let res = match arena.node(id).clone() {
+        SpecNode::Single(Label::Epsilon) => id,
+        SpecNode::Single(Label::Any) => ranges_spec_id(arena, &[('\0', char::MAX)]),
+        SpecNode::Single(Label::Symbol(c)) => ranges_spec_id(arena, &[(c, c)]),
+        SpecNode::Single(Label::Class(ref ranges)) => ranges_spec_id(arena, ranges),
+        SpecNode::Union(s1, s2) => {
+            let (b1, b2) = (utf8_node(arena, s1, done), utf8_node(arena, s2, done));
+            arena.union(b1, b2)
+        },
+        SpecNode::Concat(s1, s2) => {
+            let (b1, b2) = (utf8_node(arena, s1, done), utf8_node(arena, s2, done));
+            arena.concat(b1, b2)
+        },
+        SpecNode::Star(s) => {
+            let b = utf8_node(arena, s, done);
+            arena.star(b)
+        },
+        SpecNode::Repeat(s, min, max) => {
+            let b = utf8_node(arena, s, done);
+            arena.repeat(b, min, max)
+        },
+        SpecNode::Group(i, s) => {
+            let b = utf8_node(arena, s, done);
+            arena.intern(SpecNode::Group(i, b))
+        },
+        SpecNode::Intersection(s1, s2) => {
+            let (b1, b2) = (utf8_node(arena, s1, done), utf8_node(arena, s2, done));
+            arena.intersection(b1, b2)
+        },
+        // the complement of the encodings is restricted to valid UTF-8
+        SpecNode::Complement(s) => {
+            let any = ranges_spec_id(arena, &[('\0', char::MAX)]);
+            let all = arena.star(any);
+            let b = utf8_node(arena, s, done);
+            let not = arena.complement(b);
+            arena.intersection(all, not)
+        }
+    };
+    done.insert(id, res);
+    res
+}
+
+/// Returns the string whose characters stand for the bytes of `input`,
+/// as read by a byte-level automaton.
+pub fn byte_string(input: &[u8]) -> String {
+    input.iter().map(|&b| b as char).collect()
+}
+
+#[test]
+fn test_utf8_sequences() {
+    assert_eq!(utf8_sequences('a', 'z'), vec![vec![(0x61, 0x7A)]]);
+    assert_eq!(utf8_sequences('\0', char::MAX), vec![
+        vec![(0x00, 0x7F)],
+        vec![(0xC2, 0xDF), (0x80, 0xBF)],
+        vec![(0xE0, 0xE0), (0xA0, 0xBF), (0x80, 0xBF)],
+        vec![(0xE1, 0xEC), (0x80, 0xBF), (0x80, 0xBF)],
+        vec![(0xED, 0xED), (0x80, 0x9F), (0x80, 0xBF)],
+        vec![(0xEE, 0xEF), (0x80, 0xBF), (0x80, 0xBF)],
+        vec![(0xF0, 0xF0), (0x90, 0xBF), (0x80, 0xBF), (0x80, 0xBF)],
+        vec![(0xF1, 0xF3), (0x80, 0xBF), (0x80, 0xBF), (0x80, 0xBF)],
+        vec![(0xF4, 0xF4), (0x80, 0x8F), (0x80, 0xBF), (0x80, 0xBF)]]);
+    assert_eq!(utf8_sequences('é', 'é'), vec![vec![(0xC3, 0xC3), (0xA9, 0xA9)]]);
+}
+
+#[test]
+fn test_utf8_spec() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let patterns = [r"[a-zé]+", r"[^a]b", r".*x", r"∀[α-ω]?", r"[\x{10000}-\x{10FFFF}]"];
+    let words = ["abé", "éé", "aé", "xb", "ab", "∀b", "ab x", "∀", "∀β", "∀a", "😀", "\u{FFFF}", ""];
+
+    for p in patterns.iter() {
+        let spec = rules::regex_to_nfa_spec(&Expr::parse(p).unwrap());
+        let chars = NFABuilder::build_from_spec(spec.clone());
+        let bytes = NFABuilder::build_from_spec(utf8_spec(spec)).to_dfa();
+        for w in words.iter() {
+            assert_eq!(bytes.simulate(&byte_string(w.as_bytes())), chars.simulate(w), "{} on {:?}", p, w);
+        }
+    }
+}
+
+#[test]
+fn test_utf8_spec_id() {
+    use nfa::NFABuilder;
+    use regex_syntax::Expr;
+    use rules;
+
+    let mut arena = SpecArena::new();
+    let re = Expr::parse(r"([α-ω]x){1,100}|[α-ω]{2}").unwrap();
+    let id = rules::regex_to_spec_id(&mut arena, &re);
+    let bytes = utf8_spec_id(&mut arena, id);
+
+    // the repetition is not unrolled, and [α-ω] is converted once
+    assert!(arena.len() < 25, "{} nodes", arena.len());
+    let nfa = NFABuilder::build_from_arena(&arena, &[bytes]).to_dfa();
+    let tree = NFABuilder::build_from_spec(utf8_spec(rules::regex_to_nfa_spec(&re))).to_dfa();
+    for w in ["αx", "αxβxωx", "αβ", "αβγ", "α", "ax", ""].iter() {
+        let w = byte_string(w.as_bytes());
+        assert_eq!(nfa.longest_match(&w), tree.longest_match(&w), "{:?}", w);
+    }
+}
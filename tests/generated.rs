@@ -0,0 +1,185 @@
+//
+// generated.rs
+// Compiles the lexers generated for some specs and runs them
+//
+
+extern crate rslex;
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use rslex::codegen;
+use rslex::codegen::Options;
+use rslex::nfa::Construction;
+use rslex::parser::LexSpec;
+use rslex::parser::parse_spec;
+
+// prints the tokens of the standard input, one per line, with the
+// lexer made by LEXER
+const MAIN: &str = "
+fn main() {
+    let mut input = Vec::new();
+    ::std::io::Read::read_to_end(&mut ::std::io::stdin(), &mut input).unwrap();
+    let mut lexer = LEXER;
+    while let Some((rule, text)) = lexer.next_token() {
+        println!(\"{} {:?}\", rule, text);
+    }
+}
+";
+
+// the user code of the specs, for actions that return their text as a
+// String whether it is a &str or a &[u8]
+const USER_CODE: &str = "%%
+pub type Token = (usize, String);
+
+trait Text {
+    fn owned(&self) -> String;
+}
+
+impl Text for str {
+    fn owned(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl Text for [u8] {
+    fn owned(&self) -> String {
+        String::from_utf8_lossy(self).into_owned()
+    }
+}
+";
+
+// generates the lexer for spec, compiles it with a main function that
+// prints its tokens, and runs it on input
+fn run_lexer(name: &str, spec: &LexSpec, options: &Options, input: &[u8]) -> String {
+    let code = codegen::generate_with(spec, options).unwrap();
+    let lexer = if options.utf8_bytes { "Lexer::new(&input)" } else { "Lexer::new(::std::str::from_utf8(&input).unwrap())" };
+    let dir = env::temp_dir();
+    let source: PathBuf = dir.join(format!("rslex-{}-{}.rs", name, std::process::id()));
+    let exe: PathBuf = dir.join(format!("rslex-{}-{}", name, std::process::id()));
+    File::create(&source).and_then(|mut f| f.write_all((code + &MAIN.replace("LEXER", lexer)).as_bytes())).unwrap();
+
+    let rustc = env::var("RUSTC").unwrap_or("rustc".to_string());
+    let compiled = Command::new(rustc).arg("-A").arg("warnings").arg("-o").arg(&exe).arg(&source)
+        .output().unwrap();
+    assert!(compiled.status.success(), "{}", String::from_utf8_lossy(&compiled.stderr));
+
+    let mut child = Command::new(&exe).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&exe);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// the tokens of input by the longest matches of the NFA of the initial
+// start condition, in the format of MAIN, for specs whose rules all
+// return their id and text
+fn expected_tokens(spec: &LexSpec, input: &str) -> String {
+    let nfa = spec.condition_nfa(0);
+    let mut res = String::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        let (rule, len) = nfa.longest_match(&input[pos..]).unwrap();
+        assert!(len > 0);
+        res.push_str(&format!("{} {:?}\n", rule, &input[pos .. pos + len]));
+        pos += len;
+    }
+    res
+}
+
+#[test]
+fn test_generated_lexer() {
+    let spec = parse_spec(&(r#"
+%option set-operators
+%%
+if|else|while               return Some((0, text.owned()));
+[a-z_][a-z0-9_]*&~(.*__.*)  return Some((1, text.owned()));
+[a-z_]+                     return Some((2, text.owned()));
+[0-9]+(\.[0-9]+)?           return Some((3, text.owned()));
+(?i)select                  return Some((4, text.owned()));
+"([^"\\]|\\.)*"             return Some((5, text.owned()));
+[ \t\n]+                    return Some((6, text.owned()));
+[α-ω]+                      return Some((7, text.owned()));
+(?s).                       return Some((8, text.owned()));
+"#.to_string() + USER_CODE)).unwrap();
+    let input = "if x1 else while2 a__b 3.14 15 SeLeCt select \"a \\\"q\\\" é\" λόγος\tαβ ≠ {x}\n";
+    let expected = expected_tokens(&spec, input);
+    assert!(expected.starts_with("0 \"if\"\n6 \" \"\n1 \"x1\"\n"));
+    assert!(expected.contains("2 \"a__b\"\n") && expected.contains("7 \"αβ\"\n"));
+
+    let options = [("chars", Options::default()),
+                   ("bytes", Options { utf8_bytes: true, ..Options::default() }),
+                   ("glushkov", Options { construction: Construction::Glushkov, ..Options::default() })];
+    for &(name, ref options) in options.iter() {
+        assert_eq!(run_lexer(&format!("lexer-{}", name), &spec, options, input.as_bytes()), expected, "{}", name);
+    }
+}
+
+#[test]
+fn test_generated_conditions() {
+    let spec = parse_spec(&(r#"
+%x STR
+%%
+"                   { self.begin(STR); }
+<STR>"              { self.begin(INITIAL); }
+<STR>[^"]+          return Some((0, text.owned()));
+(?P<int>[0-9]+)(\.(?P<frac>[0-9]+))?  return Some((1, format!("{}/{}", int.unwrap().owned(), frac.map(|f| f.owned()).unwrap_or("-".to_string()))));
+[ ]
+<STR><<EOF>>        {
+    self.begin(INITIAL);
+    return Some((2, "unterminated".to_string()));
+}
+"#.to_string() + USER_CODE)).unwrap();
+    let input = "12 \"ab c\" 3.25 \"é";
+    let expected = "1 \"12/-\"\n0 \"ab c\"\n1 \"3/25\"\n0 \"é\"\n2 \"unterminated\"\n";
+
+    for &(name, ref options) in [("chars", Options::default()),
+                                 ("bytes", Options { utf8_bytes: true, ..Options::default() })].iter() {
+        assert_eq!(run_lexer(&format!("conditions-{}", name), &spec, options, input.as_bytes()), expected, "{}", name);
+    }
+}
+
+#[test]
+fn test_generated_bytes() {
+    let spec = parse_spec(&(r#"
+%%
+[a-zé]+     return Some((0, text.owned()));
+[0-9]+      return Some((1, text.owned()));
+[ ]
+#           return None;
+"#.to_string() + USER_CODE)).unwrap();
+
+    // the input after # is not read, and need not be UTF-8
+    let input = b"ab 12 \xc3\xa9t\xc3\xa9 #\xff\xfe\xc3";
+    let options = Options { utf8_bytes: true, ..Options::default() };
+    assert_eq!(run_lexer("bytes", &spec, &options, input), "0 \"ab\"\n1 \"12\"\n0 \"été\"\n");
+}
+
+#[test]
+fn test_generated_default_rule() {
+    let spec = parse_spec(&(r#"
+%%
+[0-9]+      return Some((0, text.owned()));
+[ ]
+"#.to_string() + USER_CODE)).unwrap();
+
+    // the characters that no rule matches are skipped, one at a time
+    let input = "12 x3 é4";
+    let expected = "0 \"12\"\n0 \"3\"\n0 \"4\"\n";
+    for &(name, ref options) in [("chars", Options::default()),
+                                 ("bytes", Options { utf8_bytes: true, ..Options::default() })].iter() {
+        assert_eq!(run_lexer(&format!("default-{}", name), &spec, options, input.as_bytes()), expected, "{}", name);
+    }
+
+    // so are the bytes of invalid UTF-8
+    let options = Options { utf8_bytes: true, ..Options::default() };
+    assert_eq!(run_lexer("default-invalid", &spec, &options, b"1\xff2\xc3 3\xe2\x89"), "0 \"1\"\n0 \"2\"\n0 \"3\"\n");
+}